@@ -12,13 +12,17 @@ extern crate serde_derive;
 extern crate tinytemplate;
 
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use drain_flow::{drains::simple::SingleLayer, log_group::LogGroup, record::Record};
+use drain_flow::{
+    drains::simple::SingleLayer,
+    log_group::LogGroup,
+    record::{tokens::TokenStream, Record},
+};
 
-// Really simplistic benchmark of adding new lines using a constant line
-// this is pretty unrealistic since after the first one the string interner
-// will be doing almost no work but its a start
+// Really simplistic benchmark of adding new lines using a constant line, the
+// interner is warmed first so the first iteration isn't skewed by interning
 pub fn benchmark_new_lines(c: &mut Criterion) {
     let mut drain = SingleLayer::new(vec![]).unwrap();
+    drain.warm_interner(&["Sample", "line", "with", "a", "few", "words", "to", "score"]);
     c.bench_function("new_lines", |b| {
         b.iter(|| {
             drain.process_line(black_box(
@@ -28,6 +32,79 @@ pub fn benchmark_new_lines(c: &mut Criterion) {
     });
 }
 
+// A stream made of a handful of lines repeated many times, with and without the match cache
+pub fn benchmark_repeated_lines(c: &mut Criterion) {
+    let lines = (0..50)
+        .map(|i| format!("Sample line number {} with a few words to score", i))
+        .collect::<Vec<String>>();
+    let mut group = c.benchmark_group("repeated_lines");
+    for cache in [None, Some(64)] {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.set_match_cache(cache);
+        let name = if cache.is_some() {
+            "cached"
+        } else {
+            "uncached"
+        };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for line in &lines {
+                    drain.process_line(black_box(line.clone())).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+// Lines landing in a bucket holding many groups, so every line is scored against each of them
+pub fn benchmark_crowded_bucket(c: &mut Criterion) {
+    let mut drain = SingleLayer::new(vec![]).unwrap();
+    for i in 0..50 {
+        let words = (0..8)
+            .map(|w| format!("w{}x{}", i, w))
+            .collect::<Vec<String>>();
+        drain
+            .process_line(format!("Event {}", words.join(" ")))
+            .unwrap();
+    }
+    c.bench_function("crowded_bucket", |b| {
+        b.iter(|| {
+            drain
+                .process_line(black_box(
+                    "Event w7x0 w7x1 w7x2 w7x3 w7x4 q r s".to_string(),
+                ))
+                .unwrap();
+        })
+    });
+}
+
+// A single bucket holding 5000 groups, scored serially and, with the rayon feature, in parallel
+pub fn benchmark_large_bucket(c: &mut Criterion) {
+    let mut drain = SingleLayer::new(vec![]).unwrap();
+    for i in 0..5000 {
+        let words = (0..8)
+            .map(|w| format!("w{}x{}", i, w))
+            .collect::<Vec<String>>();
+        drain
+            .process_line(format!("Event {}", words.join(" ")))
+            .unwrap();
+    }
+    let line = "Event w4999x0 w4999x1 w4999x2 w4999x3 w4999x4 q r s".to_string();
+    let mut group = c.benchmark_group("large_bucket");
+    group.bench_function("serial", |b| {
+        b.iter(|| drain.classify_line(black_box(line.clone())).unwrap())
+    });
+    #[cfg(feature = "rayon")]
+    {
+        drain.set_parallel_scoring(Some(1024));
+        group.bench_function("parallel", |b| {
+            b.iter(|| drain.classify_line(black_box(line.clone())).unwrap())
+        });
+    }
+    group.finish();
+}
+
 pub fn benchmark_calculate_score(c: &mut Criterion) {
     let rec1 = Record::new("Sample line with a few words to score".to_string());
     let rec2 = Record::new("Different log line which will not match".to_string());
@@ -36,6 +113,12 @@ pub fn benchmark_calculate_score(c: &mut Criterion) {
             rec1.calc_sim_score(black_box(&rec2));
         });
     });
+    // Gives up once the remaining positions can't beat the floor, as when scoring a crowded bucket
+    c.bench_function("calculate_simscore_above", |b| {
+        b.iter(|| {
+            rec1.calc_sim_score_above(black_box(&rec2), 4);
+        });
+    });
 }
 
 pub fn benchmark_find_variables(c: &mut Criterion) {
@@ -62,11 +145,84 @@ pub fn benchmark_add_example(c: &mut Criterion) {
     });
 }
 
+// Rendering a few thousand templates with exact spacing versus single space joins
+pub fn benchmark_template_dump(c: &mut Criterion) {
+    let streams = (0..5000)
+        .map(|i| {
+            TokenStream::from_unicode_line(&format!(
+                "Request {}  from  10.0.{}.{} completed  in {} ms",
+                i,
+                i % 256,
+                i % 7,
+                i * 3
+            ))
+        })
+        .collect::<Vec<TokenStream>>();
+    let mut group = c.benchmark_group("template_dump");
+    group.bench_function("display", |b| {
+        b.iter(|| {
+            for stream in &streams {
+                black_box(stream.to_string());
+            }
+        })
+    });
+    group.bench_function("template_string", |b| {
+        b.iter(|| {
+            for stream in &streams {
+                black_box(stream.to_template_string());
+            }
+        })
+    });
+    group.finish();
+}
+
+// Resolving every symbol of a set of templates, copying each string versus borrowing it from
+// the interner
+pub fn benchmark_resolve(c: &mut Criterion) {
+    let mut drain = SingleLayer::new(vec![]).unwrap();
+    for i in 0..500 {
+        drain
+            .process_line(format!(
+                "Worker {} finished job batch-{} in {} ms",
+                i % 17,
+                i,
+                i * 3
+            ))
+            .unwrap();
+    }
+    let symbols = drain
+        .dump_interner()
+        .into_iter()
+        .map(|(sym, _)| sym)
+        .collect::<Vec<_>>();
+    let mut group = c.benchmark_group("resolve");
+    group.bench_function("owned", |b| {
+        b.iter(|| {
+            for sym in &symbols {
+                black_box(drain.resolve(*sym));
+            }
+        })
+    });
+    group.bench_function("borrowed", |b| {
+        b.iter(|| {
+            for sym in &symbols {
+                black_box(drain.resolve_ref(*sym).len());
+            }
+        })
+    });
+    group.finish();
+}
+
 criterion_group!(
     benches,
     benchmark_new_lines,
+    benchmark_repeated_lines,
+    benchmark_crowded_bucket,
+    benchmark_large_bucket,
     benchmark_calculate_score,
     benchmark_find_variables,
-    benchmark_add_example
+    benchmark_add_example,
+    benchmark_template_dump,
+    benchmark_resolve
 );
 criterion_main!(benches);
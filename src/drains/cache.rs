@@ -0,0 +1,133 @@
+// Copyright Nicholas Harring. All rights reserved.
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the Server Side Public License, version 1, as published by MongoDB, Inc.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the Server Side Public License for more details. You should have received a copy of the
+// Server Side Public License along with this program.
+// If not, see <http://www.mongodb.com/licensing/server-side-public-license>.
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+};
+
+use rksuid::Ksuid;
+use string_interner::DefaultSymbol;
+use tracing::instrument;
+
+use crate::record::Record;
+
+/// Least recently used cache from the hash of a record's tokens to the id of the group it matched
+#[derive(Clone, Debug)]
+pub(crate) struct MatchCache {
+    capacity: usize,
+    tick: u64,
+    // token hash -> (group id, tick of last use)
+    entries: HashMap<u64, (Ksuid, u64)>,
+    // tick of last use -> token hash, oldest first
+    recency: BTreeMap<u64, u64>,
+    hits: u64,
+}
+
+impl MatchCache {
+    #[instrument(level = "trace")]
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::with_capacity(capacity),
+            recency: BTreeMap::new(),
+            hits: 0,
+        }
+    }
+
+    /// Hash the full token sequence of a record
+    #[instrument(level = "trace", skip_all)]
+    pub(crate) fn key(rec: &Record) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for token in rec {
+            DefaultSymbol::from(token).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub(crate) fn get(&mut self, key: u64) -> Option<Ksuid> {
+        self.tick += 1;
+        let (id, last_used) = self.entries.get_mut(&key)?;
+        self.recency.remove(last_used);
+        self.recency.insert(self.tick, key);
+        *last_used = self.tick;
+        self.hits += 1;
+        Some(*id)
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub(crate) fn insert(&mut self, key: u64, id: Ksuid) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some((_, last_used)) = self.entries.insert(key, (id, self.tick)) {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(self.tick, key);
+        while self.entries.len() > self.capacity {
+            match self.recency.pop_first() {
+                Some((_, oldest)) => {
+                    self.entries.remove(&oldest);
+                },
+                None => break,
+            }
+        }
+    }
+
+    /// Drop every entry, used whenever a change to the drain could alter which group a line matches
+    #[instrument(level = "trace", skip(self))]
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use rksuid::Ksuid;
+    use spectral::prelude::*;
+
+    use super::MatchCache;
+    use crate::record::Record;
+
+    #[test]
+    fn test_same_tokens_same_key() {
+        let a = Record::new("Message send failed to remote host: foo.bar.com".to_string());
+        let b = Record::new("Message send  failed to remote host: foo.bar.com".to_string());
+        let c = Record::new("Message send failed to remote host: bork.bork.com".to_string());
+        assert_that(&MatchCache::key(&a)).is_equal_to(MatchCache::key(&b));
+        assert_that(&MatchCache::key(&a)).is_not_equal_to(MatchCache::key(&c));
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = MatchCache::new(2);
+        let (a, b, c) = (Ksuid::new(), Ksuid::new(), Ksuid::new());
+        cache.insert(1, a);
+        cache.insert(2, b);
+        assert_that(&cache.get(1)).is_equal_to(Some(a));
+        cache.insert(3, c);
+        assert_that(&cache.get(2)).is_none();
+        assert_that(&cache.get(1)).is_equal_to(Some(a));
+        assert_that(&cache.get(3)).is_equal_to(Some(c));
+        assert_that(&cache.hits()).is_equal_to(3);
+    }
+}
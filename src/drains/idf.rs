@@ -0,0 +1,90 @@
+// Copyright Nicholas Harring. All rights reserved.
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the Server Side Public License, version 1, as published by MongoDB, Inc.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the Server Side Public License for more details. You should have received a copy of the
+// Server Side Public License along with this program.
+// If not, see <http://www.mongodb.com/licensing/server-side-public-license>.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use string_interner::DefaultSymbol;
+use tracing::instrument;
+
+use crate::record::Record;
+
+/// Number of lines each symbol has appeared in, used to weight rare tokens above common ones
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct DocumentFrequency {
+    lines: u64,
+    // Symbols are only meaningful to this process's interner, so counts are stored by string
+    #[serde(with = "crate::serialize::symbol_map")]
+    counts: HashMap<DefaultSymbol, u64>,
+}
+
+impl DocumentFrequency {
+    /// Count every distinct symbol in `rec` once
+    #[instrument(level = "trace", skip_all)]
+    pub(crate) fn observe(&mut self, rec: &Record) {
+        self.lines += 1;
+        let symbols = rec
+            .into_iter()
+            .map(DefaultSymbol::from)
+            .collect::<HashSet<_>>();
+        for sym in symbols {
+            *self.counts.entry(sym).or_insert(0) += 1;
+        }
+    }
+
+    /// Smoothed inverse document frequency, always at least 1 so unseen and ubiquitous symbols
+    /// still count for something
+    #[instrument(level = "trace", skip(self))]
+    pub(crate) fn weight(&self, sym: DefaultSymbol) -> f64 {
+        let seen = self.counts.get(&sym).copied().unwrap_or(0);
+        ((1 + self.lines) as f64 / (1 + seen) as f64).ln() + 1.0
+    }
+
+    /// Weight of the positions where `rec` and `candidate` agree as a fraction of the total weight
+    /// of `rec`
+    #[instrument(level = "trace", skip_all)]
+    pub(crate) fn score(&self, rec: &Record, candidate: &Record) -> f64 {
+        let total: f64 = rec.into_iter().map(|t| self.weight(t.into())).sum();
+        if total == 0.0 {
+            return 0.0;
+        }
+        rec.calc_weighted_sim_score(candidate, |t| self.weight(t.into())) / total
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use spectral::prelude::*;
+
+    use super::DocumentFrequency;
+    use crate::record::Record;
+
+    #[test]
+    fn test_rare_tokens_score_higher() {
+        let mut df = DocumentFrequency::default();
+        for i in 0..20 {
+            df.observe(&Record::new(format!("the request failed for user{}", i)));
+        }
+        df.observe(&Record::new("the quota exceeded for tenant".to_string()));
+        let rec = Record::new("the quota failed for alice".to_string());
+        let rare = Record::new("one quota exceeded by alice".to_string());
+        let common = Record::new("the request failed for bob".to_string());
+        // common shares more tokens with rec, but rare shares the distinctive ones
+        assert_that!(rec.calc_sim_score(&common)).is_greater_than(rec.calc_sim_score(&rare));
+        assert_that!(df.score(&rec, &rare)).is_greater_than(df.score(&rec, &common));
+    }
+
+    #[test]
+    fn test_unseen_symbols_have_weight() {
+        let df = DocumentFrequency::default();
+        let rec = Record::new("some brand new line".to_string());
+        assert!((df.score(&rec, &rec) - 1.0).abs() < f64::EPSILON);
+    }
+}
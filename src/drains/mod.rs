@@ -8,4 +8,29 @@
 // Server Side Public License along with this program.
 // If not, see <http://www.mongodb.com/licensing/server-side-public-license>.
 
+mod cache;
+mod idf;
+pub mod observer;
+pub mod shared;
+pub mod similarity;
 pub mod simple;
+
+
+use self::simple::Outcome;
+use crate::{error::DrainError, log_group::LogGroup};
+
+/// Operations shared by every drain implementation, so pipelines can be written against
+/// `&mut dyn Drain` or hold a `Box<dyn Drain>`
+pub trait Drain {
+    /// Cluster `line`, adding it to a matching group or creating a new one
+    fn process_line(&mut self, line: String) -> Result<Outcome, DrainError>;
+
+    /// Report which group `line` would join without modifying the drain
+    fn classify_line(&self, line: String) -> Result<Outcome, DrainError>;
+
+    /// Every log group, gathered by token length
+    fn iter_groups(&self) -> Vec<Vec<&LogGroup>>;
+
+    /// Set the similarity threshold a line must exceed to join a group
+    fn set_threshold(&mut self, numerator: u64, denominator: u64) -> Result<(), DrainError>;
+}
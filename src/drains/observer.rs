@@ -0,0 +1,34 @@
+// Copyright Nicholas Harring. All rights reserved.
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the Server Side Public License, version 1, as published by MongoDB, Inc.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the Server Side Public License for more details. You should have received a copy of the
+// Server Side Public License along with this program.
+// If not, see <http://www.mongodb.com/licensing/server-side-public-license>.
+
+use std::fmt::Debug;
+
+use rksuid::Ksuid;
+
+/// Hooks for exporting metrics out of a drain
+///
+/// Every callback has a no-op default so implementors only need to handle the events they care about.
+/// Observers are shared between clones of a drain, so callbacks take `&self` and implementations
+/// should use interior mutability (atomics, locks) for any state they keep.
+pub trait DrainObserver: Debug + Send + Sync {
+    /// Called when a line doesn't match any existing group and a new group is created for it
+    fn on_new_group(&self, _group_id: Ksuid) {}
+
+    /// Called when a line is added as an example of an existing group
+    fn on_match(&self, _group_id: Ksuid) {}
+
+    /// Called once when a group's template has gone the drain's configured number of examples
+    /// without changing
+    fn on_stable(&self, _group_id: Ksuid) {}
+
+    /// Called every time the drain's report interval of lines has been processed with the ids of
+    /// the groups created since the previous report, oldest first
+    fn on_report(&self, _new_groups: &[Ksuid]) {}
+}
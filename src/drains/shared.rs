@@ -0,0 +1,109 @@
+// Copyright Nicholas Harring. All rights reserved.
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the Server Side Public License, version 1, as published by MongoDB, Inc.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the Server Side Public License for more details. You should have received a copy of the
+// Server Side Public License along with this program.
+// If not, see <http://www.mongodb.com/licensing/server-side-public-license>.
+
+use std::sync::Arc;
+
+use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::instrument;
+
+use super::simple::{Outcome, SingleLayer};
+use crate::error::DrainError;
+
+/// A [SingleLayer] which can be shared between threads for read heavy workloads
+///
+/// Clones share the same drain. Any number of threads can call [SharedDrain::classify_line]
+/// concurrently under a read lock while [SharedDrain::process_line] takes the write lock, so
+/// readers always see a drain between lines rather than part way through one.
+#[derive(Clone, Debug)]
+pub struct SharedDrain {
+    inner: Arc<RwLock<SingleLayer>>,
+}
+
+impl SharedDrain {
+    #[instrument(skip(drain))]
+    pub fn new(drain: SingleLayer) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(drain)),
+        }
+    }
+
+    /// Report which group `line` would join without modifying the drain, see
+    /// [SingleLayer::classify_line]
+    #[instrument(skip(self, line))]
+    pub fn classify_line(&self, line: String) -> Result<Outcome, DrainError> {
+        self.inner.read().classify_line(line)
+    }
+
+    /// Cluster `line` under the write lock, see [SingleLayer::process_line]
+    #[instrument(skip(self, line))]
+    pub fn process_line(&self, line: String) -> Result<Outcome, DrainError> {
+        self.inner.write().process_line(line)
+    }
+
+    /// Lock the drain for reading, for queries beyond [SharedDrain::classify_line]. Writers wait
+    /// until the guard is dropped.
+    #[instrument(skip(self), level = "trace")]
+    pub fn read(&self) -> RwLockReadGuard<'_, SingleLayer> {
+        self.inner.read()
+    }
+
+    /// Lock the drain for writing, for configuration changes
+    #[instrument(skip(self), level = "trace")]
+    pub fn write(&self) -> RwLockWriteGuard<'_, SingleLayer> {
+        self.inner.write()
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use std::thread;
+
+    use spectral::prelude::*;
+
+    use super::SharedDrain;
+    use crate::drains::simple::{Outcome, SingleLayer};
+
+    #[test]
+    fn test_concurrent_classify_line() {
+        let shared = SharedDrain::new(SingleLayer::new(vec![]).unwrap());
+        let disk = shared
+            .process_line("Disk sda1 is 91% full".to_string())
+            .unwrap()
+            .group_id()
+            .unwrap();
+        shared
+            .process_line("User alice logged in".to_string())
+            .unwrap();
+        let readers = (0..8)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    (0..100)
+                        .map(|i| {
+                            shared
+                                .classify_line(format!("Disk sdb{} is 91% full", i))
+                                .unwrap()
+                        })
+                        .collect::<Vec<Outcome>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        for i in 0..20 {
+            shared
+                .process_line(format!("Unrelated event number {}", i))
+                .unwrap();
+        }
+        for reader in readers {
+            let outcomes = reader.join().unwrap();
+            assert!(outcomes.iter().all(|o| *o == Outcome::Matched(disk)));
+        }
+        assert_that!(shared.read().group_count()).is_equal_to(3);
+    }
+}
@@ -0,0 +1,59 @@
+// Copyright Nicholas Harring. All rights reserved.
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the Server Side Public License, version 1, as published by MongoDB, Inc.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the Server Side Public License for more details. You should have received a copy of the
+// Server Side Public License along with this program.
+// If not, see <http://www.mongodb.com/licensing/server-side-public-license>.
+
+use std::fmt::Debug;
+
+use fraction::{BigInt, Ratio};
+
+use crate::record::Record;
+
+/// A pluggable comparison between a line and the template of a group
+///
+/// Implementations are shared between clones of a drain so they must be `Send + Sync`. Scores are
+/// compared against the drain's threshold, a line joins the best scoring group when its score is
+/// above it.
+pub trait Similarity: Debug + Send + Sync {
+    /// How alike `rec` is to the group template `template`, from 0 (nothing alike) to 1 (identical)
+    fn score(&self, rec: &Record, template: &Record) -> Ratio<BigInt>;
+}
+
+/// The default comparison, the fraction of positions holding equal tokens. Wildcards in the
+/// template never count as equal.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Positional;
+
+impl Similarity for Positional {
+    fn score(&self, rec: &Record, template: &Record) -> Ratio<BigInt> {
+        if rec.is_empty() {
+            return Ratio::new(BigInt::from(0), BigInt::from(1));
+        }
+        Ratio::new(
+            BigInt::from(rec.calc_sim_score(template)),
+            BigInt::from(rec.len()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use fraction::{BigInt, Ratio};
+    use spectral::prelude::*;
+
+    use super::{Positional, Similarity};
+    use crate::record::Record;
+
+    #[test]
+    fn test_positional_is_fraction_of_equal_tokens() {
+        let rec = Record::new("Disk sda1 is 91% full".to_string());
+        let template = Record::new("Disk sdb2 is 91% full".to_string());
+        assert_that!(Positional.score(&rec, &template))
+            .is_equal_to(Ratio::new(BigInt::from(4), BigInt::from(5)));
+    }
+}
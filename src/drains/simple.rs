@@ -8,23 +8,246 @@
 // Server Side Public License along with this program.
 // If not, see <http://www.mongodb.com/licensing/server-side-public-license>.
 
-use std::{collections::HashMap, fmt, sync::Arc};
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    io::{BufRead, Write},
+    str::FromStr,
+    sync::{
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
 
-use anyhow::{anyhow, Error};
-use fraction::{BigInt, FromPrimitive, Ratio};
+#[cfg(feature = "bincode")]
+use std::io::{ErrorKind, Read};
+
+#[cfg(feature = "bincode")]
+use bincode::Options;
+use fraction::{BigInt, Ratio, ToPrimitive};
 use joinery::{Joinable, JoinableIterator};
 use lazy_static::lazy_static;
-use parking_lot::RwLock;
+use parking_lot::{MappedRwLockReadGuard, RwLock, RwLockReadGuard};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 use regex::Regex;
+use rksuid::Ksuid;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use string_interner::{DefaultSymbol, StringInterner};
-use tracing::instrument;
+use tracing::{instrument, warn};
 
-use crate::{log_group::LogGroup, record::Record};
+use super::{
+    cache::MatchCache, idf::DocumentFrequency, observer::DrainObserver, similarity::Similarity,
+    Drain,
+};
+use crate::{
+    error::DrainError,
+    log_group::{ExamplePolicy, LogGroup},
+    record::{
+        tokens::{Token, TokenizerOptions},
+        PositionWeighting, Record,
+    },
+};
 
 lazy_static! {
+    /// Strings of every drain, record and token in the process. Symbols are never freed: the
+    /// interner is shared across drains so none of them can tell when a symbol is unused, and
+    /// string-interner can't remove entries without renumbering the symbols already handed out.
     pub(crate) static ref INTERNER: Arc<RwLock<StringInterner>> =
         Arc::new(RwLock::new(StringInterner::default()));
 }
+/// What to do with lines which have more tokens than [SingleLayer::set_max_tokens] allows
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LongLinePolicy {
+    /// Drop the line with [Outcome::TooLong]
+    Reject,
+    /// Keep the leading tokens and replace the rest with a single `...` marker token
+    Truncate,
+}
+
+/// What the positional similarity score is divided by before comparing it to the threshold
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScoreDenominator {
+    /// The number of tokens in the line, so every wildcard in a template counts as a mismatch
+    #[default]
+    RecordLength,
+    /// The number of positions in the template which aren't wildcards, so a line is judged only
+    /// on the positions the template still holds fixed. A template of only wildcards never
+    /// matches.
+    LiteralPositions,
+}
+
+/// Result of processing a single line through a drain
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    /// The line didn't match any existing group and created a new one with this id
+    NewGroup(Ksuid),
+    /// The line was added as an example of the group with this id
+    Matched(Ksuid),
+    /// The line had fewer tokens than the drain's minimum and was dropped
+    TooShort,
+    /// The line had more tokens than the drain's maximum and was rejected
+    TooLong,
+    /// The drain is frozen and the line didn't match any existing group
+    Unmatched,
+    /// The drain reached its group ceiling and the line was added to the catch all group with
+    /// this id, see [SingleLayer::set_max_groups]
+    Overflow(Ksuid),
+}
+
+impl Outcome {
+    /// Whether processing the line created a new group
+    #[must_use]
+    pub fn is_new_group(&self) -> bool {
+        matches!(self, Outcome::NewGroup(_))
+    }
+
+    /// The id of the group the line was placed in, if any
+    #[must_use]
+    pub fn group_id(&self) -> Option<Ksuid> {
+        match self {
+            Outcome::NewGroup(id) | Outcome::Matched(id) | Outcome::Overflow(id) => Some(*id),
+            Outcome::TooShort | Outcome::TooLong | Outcome::Unmatched => None,
+        }
+    }
+}
+
+/// How many lines of a held out sample an existing drain recognizes, see [SingleLayer::coverage]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Coverage {
+    /// Lines which matched an existing group
+    pub matched: usize,
+    /// Lines which would have started a new group
+    pub unmatched: usize,
+    /// Lines dropped for being too short or too long
+    pub skipped: usize,
+}
+
+impl Coverage {
+    /// Fraction of the classified lines which matched, skipped lines aren't counted. Returns 0
+    /// when no lines were classified.
+    #[must_use]
+    pub fn ratio(&self) -> f64 {
+        match self.matched + self.unmatched {
+            0 => 0.0,
+            total => self.matched as f64 / total as f64,
+        }
+    }
+}
+
+/// Rendered templates of two drains split by which drain has them, see [SingleLayer::template_diff]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TemplateDiff {
+    /// Templates only the drain `template_diff` was called on has
+    pub only_self: BTreeSet<String>,
+    /// Templates only the other drain has
+    pub only_other: BTreeSet<String>,
+    /// Templates both drains have
+    pub common: BTreeSet<String>,
+}
+
+/// Rendered template of every group in a drain keyed by group id, see [SingleLayer::snapshot]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    templates: BTreeMap<String, String>,
+}
+
+impl Snapshot {
+    /// Rendered templates keyed by group id
+    #[must_use]
+    pub fn templates(&self) -> &BTreeMap<String, String> {
+        &self.templates
+    }
+
+    /// Compare this snapshot with one taken `later`, groups are matched up by id
+    #[must_use]
+    pub fn diff(&self, later: &Snapshot) -> DriftReport {
+        let mut report = DriftReport::default();
+        for (id, template) in &self.templates {
+            match later.templates.get(id) {
+                None => {
+                    report.removed.insert(id.clone(), template.clone());
+                },
+                Some(now) if now != template => {
+                    report
+                        .modified
+                        .insert(id.clone(), (template.clone(), now.clone()));
+                },
+                Some(_) => {},
+            }
+        }
+        for (id, template) in &later.templates {
+            if !self.templates.contains_key(id) {
+                report.added.insert(id.clone(), template.clone());
+            }
+        }
+        report
+    }
+}
+
+/// Templates which changed between two [Snapshot]s, keyed by group id
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DriftReport {
+    /// Groups created since the earlier snapshot
+    pub added: BTreeMap<String, String>,
+    /// Groups which no longer exist, such as after pruning
+    pub removed: BTreeMap<String, String>,
+    /// Groups whose template changed, as the earlier and later template
+    pub modified: BTreeMap<String, (String, String)>,
+}
+
+impl DriftReport {
+    /// Whether nothing changed between the snapshots
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// Ready made domain regexes for [SingleLayer::with_presets]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DomainPreset {
+    /// Dotted quad IPv4 addresses
+    IPv4,
+    /// Hyphenated UUIDs
+    UUID,
+    /// ISO 8601 dates with an optional time of day and zone
+    Timestamp,
+    /// Email addresses
+    Email,
+}
+
+impl DomainPreset {
+    /// The regular expression masking this preset's values
+    #[must_use]
+    pub fn pattern(self) -> &'static str {
+        match self {
+            DomainPreset::IPv4 => r"\b(?:[0-9]{1,3}\.){3}[0-9]{1,3}\b",
+            DomainPreset::UUID => r"\b[0-9A-Fa-f]{8}-(?:[0-9A-Fa-f]{4}-){3}[0-9A-Fa-f]{12}\b",
+            DomainPreset::Timestamp => {
+                r"\b[0-9]{4}-[0-9]{2}-[0-9]{2}(?:[T ][0-9]{2}:[0-9]{2}:[0-9]{2}(?:[.,][0-9]+)?(?:Z|[+-][0-9]{2}:?[0-9]{2})?)?\b"
+            },
+            DomainPreset::Email => r"\b[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}\b",
+        }
+    }
+}
+
+/// Function rewriting a token, see [SingleLayer::set_first_token_normalizer]
+pub type TokenNormalizer = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Rewrites the first token of a line before it is used as a bucket key
+#[derive(Clone)]
+struct FirstTokenNormalizer(TokenNormalizer);
+
+impl fmt::Debug for FirstTokenNormalizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FirstTokenNormalizer")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SingleLayer {
     pub domain: Vec<Regex>,
@@ -32,11 +255,48 @@ pub struct SingleLayer {
     base_layer: HashMap<usize, HashMap<DefaultSymbol, Vec<LogGroup>>>,
     pub threshold: Ratio<BigInt>,
     strings: Arc<RwLock<StringInterner>>,
+    tokenizer: TokenizerOptions,
+    prefix_depth: Option<usize>,
+    fuzzy_first_token: bool,
+    first_token_normalizer: Option<FirstTokenNormalizer>,
+    unify_endpoints: bool,
+    position_weighting: PositionWeighting,
+    score_denominator: ScoreDenominator,
+    alignment_fallback: Option<usize>,
+    max_literal_mismatches: Option<usize>,
+    #[cfg(feature = "rayon")]
+    parallel_scoring: Option<usize>,
+    coalesce_numeric: bool,
+    example_policy: ExamplePolicy,
+    min_tokens: usize,
+    max_tokens: Option<(usize, LongLinePolicy)>,
+    frozen: bool,
+    max_groups: Option<usize>,
+    // Absorbs lines which would have created a group beyond `max_groups`
+    catch_all: Option<LogGroup>,
+    // Groups in base_layer, kept as they're filed so the ceiling check doesn't walk every bucket
+    groups_filed: usize,
+    stable_after: Option<usize>,
+    match_cache: Option<MatchCache>,
+    idf: Option<DocumentFrequency>,
+    similarity: Option<Arc<dyn Similarity>>,
+    report_interval: Option<usize>,
+    lines_since_report: usize,
+    // Groups created since the last report, only tracked while a report interval is set
+    unreported_groups: Vec<Ksuid>,
+    // Percentage bucket -> number of matched lines whose score fell in it
+    score_buckets: BTreeMap<u8, usize>,
+    observer: Option<Arc<dyn DrainObserver>>,
 }
 
 impl<'a> SingleLayer {
+    /// Examples kept by the catch all group, see [SingleLayer::set_max_groups]
+    const CATCH_ALL_EXAMPLES: usize = 10;
+
+    /// Create a drain whose `domain` regular expressions mask matching text as `*` before lines
+    /// are tokenized, so fields with expected variation don't split groups
     #[instrument(skip(domain))]
-    pub fn new(domain: Vec<String>) -> Result<Self, Error> {
+    pub fn new(domain: Vec<String>) -> Result<Self, DrainError> {
         let patterns = domain
             .iter()
             .map(|s| Regex::new(s))
@@ -46,177 +306,2934 @@ impl<'a> SingleLayer {
             base_layer: HashMap::new(),
             threshold: Ratio::from_float::<f32>(0.5).expect("0.5 converts into a ratio"),
             strings: INTERNER.clone(),
+            tokenizer: TokenizerOptions::default(),
+            prefix_depth: None,
+            fuzzy_first_token: false,
+            first_token_normalizer: None,
+            unify_endpoints: false,
+            position_weighting: PositionWeighting::Uniform,
+            score_denominator: ScoreDenominator::RecordLength,
+            alignment_fallback: None,
+            max_literal_mismatches: None,
+            #[cfg(feature = "rayon")]
+            parallel_scoring: None,
+            coalesce_numeric: false,
+            example_policy: ExamplePolicy::All,
+            min_tokens: 0,
+            max_tokens: None,
+            frozen: false,
+            max_groups: None,
+            catch_all: None,
+            groups_filed: 0,
+            stable_after: None,
+            match_cache: None,
+            idf: None,
+            similarity: None,
+            report_interval: None,
+            lines_since_report: 0,
+            unreported_groups: Vec::new(),
+            score_buckets: BTreeMap::new(),
+            observer: None,
         })
     }
 
+    /// Create a drain masking the values of the selected `presets`, see [SingleLayer::new]
+    #[instrument]
+    pub fn with_presets(presets: &[DomainPreset]) -> Self {
+        Self::new(presets.iter().map(|p| p.pattern().to_string()).collect())
+            .expect("preset patterns compile")
+    }
+
+    /// Split syslog style `app[1234]:` words so the process id can become a variable while the
+    /// program name stays literal, giving templates like `sshd[*]:`
     #[instrument(skip(self))]
-    pub fn set_threshold(&mut self, numerator: u64, denominator: u64) -> Result<(), Error> {
-        let numer = BigInt::from_u64(numerator)
-            .ok_or_else(|| anyhow!("unable to make numerator from {}", numerator))?;
-        let denom = BigInt::from_u64(denominator)
-            .ok_or_else(|| anyhow!("unable to make denominator from {}", denominator))?;
-        let new_ratio = Ratio::new(numer, denom);
-        self.threshold = new_ratio;
-        Ok(())
+    pub fn set_split_pid(&mut self, split: bool) {
+        self.tokenizer.split_pid = split;
     }
 
-    /// Accepts a line of input for processing against existing records
-    ///
-    /// Return
-    /// Ok(true) when a new entry is added
-    /// Ok(false) when the line matched an existing entry
-    /// Err(e) for errors during processing
-    #[instrument(skip(self, line))]
-    pub fn process_line(&mut self, line: String) -> Result<bool, Error> {
-        if line.is_empty() {
-            return Ok(false);
+    /// Split bracketed request ids such as `(id=12345)` so the id becomes a variable while the
+    /// brackets and label stay literal, giving templates like `(id=*)`
+    #[instrument(skip(self))]
+    pub fn set_split_bracketed_ids(&mut self, split: bool) {
+        self.tokenizer.split_bracketed_ids = split;
+    }
+
+    /// Split words on `chars` after splitting on whitespace, so `user:alice` and `user:bob` share a
+    /// template of `user : *`. An empty list turns splitting off.
+    #[instrument(skip(self))]
+    pub fn set_split_chars(&mut self, chars: Vec<char>) {
+        self.tokenizer.split_chars = chars;
+    }
+
+    /// Trim separators like the `:` in `host:` from tokens and drop punctuation only tokens such as
+    /// `->`, so lines which only differ in punctuation cluster together
+    #[instrument(skip(self))]
+    pub fn set_strip_punctuation(&mut self, strip: bool) {
+        self.tokenizer.strip_punctuation = strip;
+    }
+
+    /// Intern tokens in lower case so `ERROR`, `Error` and `error` share one symbol, keeping the
+    /// interner small on logs with inconsistent casing. Templates still show the case of the line
+    /// that created them.
+    #[instrument(skip(self))]
+    pub fn set_case_fold(&mut self, fold: bool) {
+        self.tokenizer.case_fold = fold;
+    }
+
+    /// Treat IP addresses and dotted hostnames at the same position as equal when scoring, so a
+    /// service logged by name in some lines and by address in others forms a single group. Only
+    /// applies to the default positional scoring, not [SingleLayer::set_idf_scoring].
+    #[instrument(skip(self))]
+    pub fn set_unify_endpoints(&mut self, unify: bool) {
+        self.unify_endpoints = unify;
+        self.clear_match_cache();
+    }
+
+    /// Weight positions unevenly when scoring, for logs whose lines share boilerplate prefixes and
+    /// carry their distinctive content at the end. Only applies to the default positional scoring
+    /// and takes precedence over [SingleLayer::set_unify_endpoints].
+    #[instrument(skip(self))]
+    pub fn set_position_weighting(&mut self, weighting: PositionWeighting) {
+        self.position_weighting = weighting;
+        self.clear_match_cache();
+    }
+
+    /// Choose what the positional score is divided by. [ScoreDenominator::LiteralPositions] stops
+    /// the wildcards a group has accumulated from counting against new lines, which makes
+    /// generalized groups easier to join than with the default. Only applies to the default
+    /// positional scoring.
+    #[instrument(skip(self))]
+    pub fn set_score_denominator(&mut self, denominator: ScoreDenominator) {
+        self.score_denominator = denominator;
+        self.clear_match_cache();
+    }
+
+    /// When a line matches no group of its own length, look for groups up to `max_difference`
+    /// tokens longer or shorter and score them by the longest common subsequence of tokens, so a
+    /// line with a word inserted or removed still joins its group. The score is divided by the
+    /// longer of the two lengths. `None` disables the fallback.
+    #[instrument(skip(self))]
+    pub fn set_alignment_fallback(&mut self, max_difference: Option<usize>) {
+        self.alignment_fallback = max_difference;
+        self.clear_match_cache();
+    }
+
+    /// When a line scores below the threshold against every group of its bucket, still join the
+    /// group whose literal positions differ from the line in at most `budget` places, preferring
+    /// the fewest differences. The differing positions become variables. Helps recall on noisy
+    /// logs where a single stray word would otherwise start a new group. `None` disables it.
+    #[instrument(skip(self))]
+    pub fn set_max_literal_mismatches(&mut self, budget: Option<usize>) {
+        self.max_literal_mismatches = budget;
+        self.clear_match_cache();
+    }
+
+    /// Score the groups of a bucket on the rayon thread pool once it holds at least `min_groups`
+    /// groups. Ties go to the oldest group just like serial scoring, so the chosen group doesn't
+    /// depend on thread timing. Only applies to the default positional scoring. `None` disables it.
+    /// The setting is saved with the drain even without the `rayon` feature, which ignores it.
+    #[cfg(feature = "rayon")]
+    #[instrument(skip(self))]
+    pub fn set_parallel_scoring(&mut self, min_groups: Option<usize>) {
+        self.parallel_scoring = min_groups;
+    }
+
+    /// Split `key=value` words so the value can become a variable while the key stays literal,
+    /// letting `status=200` and `status=404` share a template of `status=*`
+    #[instrument(skip(self))]
+    pub fn set_split_key_value(&mut self, split: bool) {
+        self.tokenizer.split_key_value = split;
+    }
+
+    /// Bucket lines on their first `depth` tokens instead of only the first one, like the parse
+    /// tree of the original DRAIN algorithm. Tokens containing digits route as a wildcard so a
+    /// leading timestamp or id doesn't scatter otherwise identical lines. `None` restores first
+    /// token bucketing. Existing groups are moved to their new buckets.
+    #[instrument(skip(self))]
+    pub fn set_prefix_depth(&mut self, depth: Option<usize>) {
+        self.prefix_depth = depth.map(|d| d.max(1));
+        self.rebucket();
+    }
+
+    /// When the first token of a line is a variable such as a timestamp or PID, bucket on the first
+    /// literal token instead, or on length alone if every token is a variable. Keywords such as HTTP
+    /// methods and booleans count as literal, see [crate::record::tokens::Grokker::is_keyword].
+    /// Has no effect when [SingleLayer::set_prefix_depth] is in use. Existing groups are moved to
+    /// their new buckets.
+    #[instrument(skip(self))]
+    pub fn set_fuzzy_first_token(&mut self, fuzzy: bool) {
+        self.fuzzy_first_token = fuzzy;
+        self.rebucket();
+    }
+
+    /// Rewrite the first token of each line with `normalizer` before bucketing on it, for instance
+    /// to strip a leading timestamp so lines logged at different times share a bucket. Applies to
+    /// first token bucketing and the first word of [SingleLayer::set_prefix_depth] routes, but not
+    /// to [SingleLayer::set_fuzzy_first_token]. Templates are unaffected. Existing groups are moved
+    /// to their new buckets.
+    #[instrument(skip(self, normalizer))]
+    pub fn set_first_token_normalizer(&mut self, normalizer: Option<TokenNormalizer>) {
+        self.first_token_normalizer = normalizer.map(FirstTokenNormalizer);
+        self.rebucket();
+    }
+
+    /// Key of the bucket within its length layer that `rec` belongs in
+    #[instrument(skip_all, level = "trace")]
+    fn bucket_key(&self, rec: &Record) -> Option<DefaultSymbol> {
+        match self.prefix_depth {
+            None if self.fuzzy_first_token => {
+                if rec.is_empty() {
+                    return None;
+                }
+                let literal = rec.into_iter().find(|t| match t {
+                    Token::Wildcard => false,
+                    t => match Token::from_parse(&t.to_string()) {
+                        Token::Value(_) => true,
+                        Token::TypedMatch(grokker) => grokker.is_keyword(),
+                        Token::Wildcard => false,
+                    },
+                });
+                match literal {
+                    Some(t) => Some(t.into()),
+                    None => Some(self.strings.write().get_or_intern_static("*")),
+                }
+            },
+            None if self.first_token_normalizer.is_none() && self.domain.is_empty() => rec.first(),
+            None => {
+                let word = self.first_word(&rec.into_iter().next()?);
+                Some(self.strings.write().get_or_intern(word))
+            },
+            Some(depth) => {
+                if rec.is_empty() {
+                    return None;
+                }
+                let route = rec
+                    .into_iter()
+                    .take(depth)
+                    .enumerate()
+                    .map(|(idx, t)| {
+                        let word = if idx == 0 {
+                            self.first_word(&t)
+                        } else {
+                            t.to_string()
+                        };
+                        if word.chars().any(|c| c.is_ascii_digit()) {
+                            "*".to_string()
+                        } else {
+                            word
+                        }
+                    })
+                    .collect::<Vec<String>>()
+                    .join(" ");
+                Some(self.strings.write().get_or_intern(route))
+            },
         }
-        let new_record = Record::new(line);
-        let length = new_record.len();
-        let first = new_record.first().expect("records have first tokens");
-        if let Some(second_layer) = self.base_layer.get_mut(&length) {
-            match second_layer.get_mut(&first) {
-                Some(log_groups) => {
-                    let (score, offset) = log_groups.iter_mut().enumerate().fold(
-                        (
-                            0, // best score
-                            0, // index of best score LogGroup
-                        ),
-                        |mut acc, elem| {
-                            let score = new_record.clone().calc_sim_score(elem.1.event());
-                            if score > acc.0 {
-                                acc = (score, elem.0); // overwrite state with new values
-                            }
-                            acc
-                        },
-                    );
-                    let score_ratio =
-                        Ratio::<BigInt>::new(BigInt::from(score), BigInt::from(length));
-                    if let true = score_ratio > self.threshold {
-                        // add this record's uid to the list of examples for the log group
-                        log_groups[offset].add_example(new_record);
-                        Ok(false)
-                    } else {
-                        log_groups.push(LogGroup::new(new_record));
-                        Ok(true)
-                    }
-                },
-                None => {
-                    second_layer.insert(first, vec![LogGroup::new(new_record)]);
-                    Ok(true)
-                },
-            }
-        } else {
-            self.base_layer.insert(length, HashMap::new());
-            let second_layer = self
-                .base_layer
-                .get_mut(&length)
-                .expect("We just inserted this map");
-            second_layer.insert(first, vec![LogGroup::new(new_record)]);
-            Ok(true)
+    }
+
+    /// Text of the first token as used for bucketing, masked by the domain regexes so groups
+    /// created before a pattern was added route like new lines, then passed through the first
+    /// token normalizer
+    fn first_word(&self, token: &Token) -> String {
+        let word = self.mask(token.to_string());
+        match &self.first_token_normalizer {
+            Some(FirstTokenNormalizer(normalize)) => normalize(&word),
+            None => word,
+        }
+    }
+
+    /// Move every group into the bucket its base event belongs in under the current
+    /// configuration. Call this after changing [SingleLayer::domain] on a drain which already has
+    /// groups, so groups whose first token is now masked are found by new lines again. Setters
+    /// which change bucketing do this themselves.
+    #[instrument(skip(self))]
+    pub fn rebucket(&mut self) {
+        let mut groups = std::mem::take(&mut self.base_layer)
+            .into_values()
+            .flat_map(HashMap::into_values)
+            .flatten()
+            .collect::<Vec<LogGroup>>();
+        self.groups_filed = 0;
+        // oldest first so buckets which gain groups from several others keep creation order, which
+        // breaks ties when scoring
+        groups.sort_by_key(LogGroup::sequence);
+        for group in groups {
+            self.insert_group(group);
+        }
+        self.clear_match_cache();
+    }
+
+    /// File `group` under the length and bucket key of its base event
+    #[instrument(skip_all, level = "trace")]
+    fn insert_group(&mut self, group: LogGroup) {
+        let length = group.event().len();
+        let key = self
+            .bucket_key(group.event())
+            .expect("log groups have non-empty events");
+        self.base_layer
+            .entry(length)
+            .or_default()
+            .entry(key)
+            .or_default()
+            .push(group);
+        self.groups_filed += 1;
+    }
+
+    /// Remember which group recently seen lines matched so repeats of an identical line skip
+    /// scoring. The cache holds up to `capacity` lines, `None` disables it. It is cleared whenever a
+    /// group is created, a template changes or a scoring setting such as the threshold changes. The
+    /// cache is bypassed while [SingleLayer::set_idf_scoring] is enabled, as token weights change
+    /// with every line.
+    #[instrument(skip(self))]
+    pub fn set_match_cache(&mut self, capacity: Option<usize>) {
+        self.match_cache = capacity.map(MatchCache::new);
+    }
+
+    /// Forget every cached match, for when something other than the line decides where it goes
+    fn clear_match_cache(&mut self) {
+        if let Some(cache) = self.match_cache.as_mut() {
+            cache.clear();
         }
     }
 
+    /// Weight matching tokens by how rare they are across every line this drain has seen rather
+    /// than counting each match equally, so shared common words like `the` contribute less to the
+    /// similarity score than distinctive tokens. Enabling this starts with empty statistics.
+    #[instrument(skip(self))]
+    pub fn set_idf_scoring(&mut self, enabled: bool) {
+        self.idf = enabled.then(DocumentFrequency::default);
+        self.clear_match_cache();
+    }
+
+    /// Number of lines which were matched through the match cache rather than by scoring
     #[instrument(skip(self), level = "trace")]
-    pub fn iter_groups(&self) -> Vec<Vec<&LogGroup>> {
-        let mut results: Vec<Vec<&LogGroup>> = Vec::new();
-        for length in self.base_layer.keys() {
-            let mut groups = vec![];
-            for (_, grp) in self.base_layer.get(length).unwrap().iter() {
-                for g in grp {
-                    groups.push(g);
-                }
+    pub fn match_cache_hits(&self) -> u64 {
+        self.match_cache.as_ref().map_or(0, MatchCache::hits)
+    }
+
+    /// Notify the observer through [DrainObserver::on_stable] when a group has gone
+    /// `stable_after` examples without discovering a new variable
+    #[instrument(skip(self))]
+    pub fn set_stable_after(&mut self, stable_after: Option<usize>) {
+        self.stable_after = stable_after;
+    }
+
+    /// Stop creating new groups, lines which don't match an existing group are reported as
+    /// [Outcome::Unmatched] instead. Matching lines are still added as examples.
+    #[instrument(skip(self))]
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    /// Stop creating groups once the drain holds `max_groups` of them, bounding memory on streams
+    /// of unique lines. Lines which would have created another group are added to a single catch
+    /// all group with a template of `*` instead, reported as [Outcome::Overflow] with its id. The
+    /// catch all group keeps only its most recent examples and isn't counted by
+    /// [SingleLayer::group_count]. `None` removes the ceiling, which is the default.
+    #[instrument(skip(self))]
+    pub fn set_max_groups(&mut self, max_groups: Option<usize>) {
+        self.max_groups = max_groups;
+    }
+
+    /// The group absorbing lines beyond [SingleLayer::set_max_groups], once the ceiling has been
+    /// reached
+    #[instrument(skip(self), level = "trace")]
+    pub fn catch_all(&self) -> Option<&LogGroup> {
+        self.catch_all.as_ref()
+    }
+
+    /// Whether [SingleLayer::freeze] has been called on this drain
+    #[instrument(skip(self), level = "trace")]
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// Lines with fewer than `min_tokens` tokens are dropped with [Outcome::TooShort] instead of
+    /// being clustered. Lines without any tokens are always dropped.
+    #[instrument(skip(self))]
+    pub fn set_min_tokens(&mut self, min_tokens: usize) {
+        self.min_tokens = min_tokens;
+    }
+
+    /// Lines with more than `max_tokens` tokens are handled according to `policy` rather than
+    /// scored in full. `None` leaves line length unbounded, which is the default.
+    #[instrument(skip(self))]
+    pub fn set_max_tokens(&mut self, max_tokens: Option<usize>, policy: LongLinePolicy) {
+        self.max_tokens = max_tokens.map(|m| (m.max(1), policy));
+    }
+
+    /// Tokenize `line` and apply the token count limits, lines which are dropped are returned as
+    /// the error with the [Outcome] to report
+    #[instrument(skip_all, level = "trace")]
+    fn admit_line(&self, line: String) -> Result<Record, Outcome> {
+        let mut rec = Record::with_options(self.mask(line), &self.tokenizer);
+        if rec.len() < self.min_tokens.max(1) {
+            return Err(Outcome::TooShort);
+        }
+        match self.max_tokens {
+            Some((max, LongLinePolicy::Reject)) if rec.len() > max => Err(Outcome::TooLong),
+            Some((max, LongLinePolicy::Truncate)) if rec.len() > max => {
+                rec.inner.truncate(max, "...");
+                Ok(rec)
+            },
+            _ => Ok(rec),
+        }
+    }
+
+    /// Replace every match of the domain regexes in `line` with `*`
+    #[instrument(skip_all, level = "trace")]
+    fn mask(&self, mut line: String) -> String {
+        for pattern in &self.domain {
+            if let Cow::Owned(masked) = pattern.replace_all(&line, "*") {
+                line = masked;
             }
-            results.push(groups);
         }
-        results
+        line
     }
 
+    /// Build an empty drain sharing this drain's configuration but none of its groups or observer
     #[instrument(skip(self), level = "trace")]
-    pub fn resolve(&self, sym: DefaultSymbol) -> String {
-        self.strings
-            .read()
-            .resolve(sym)
-            .expect("symbols must resolve")
-            .to_owned()
+    fn empty_like(&self) -> Self {
+        Self {
+            domain: self.domain.clone(),
+            base_layer: HashMap::new(),
+            threshold: self.threshold.clone(),
+            strings: self.strings.clone(),
+            tokenizer: self.tokenizer.clone(),
+            prefix_depth: self.prefix_depth,
+            fuzzy_first_token: self.fuzzy_first_token,
+            first_token_normalizer: self.first_token_normalizer.clone(),
+            unify_endpoints: self.unify_endpoints,
+            position_weighting: self.position_weighting,
+            score_denominator: self.score_denominator,
+            alignment_fallback: self.alignment_fallback,
+            max_literal_mismatches: self.max_literal_mismatches,
+            #[cfg(feature = "rayon")]
+            parallel_scoring: self.parallel_scoring,
+            coalesce_numeric: self.coalesce_numeric,
+            example_policy: self.example_policy,
+            min_tokens: self.min_tokens,
+            max_tokens: self.max_tokens,
+            frozen: false,
+            max_groups: self.max_groups,
+            catch_all: None,
+            groups_filed: 0,
+            stable_after: self.stable_after,
+            match_cache: self
+                .match_cache
+                .as_ref()
+                .map(|c| MatchCache::new(c.capacity())),
+            idf: self.idf.as_ref().map(|_| DocumentFrequency::default()),
+            similarity: self.similarity.clone(),
+            report_interval: self.report_interval,
+            lines_since_report: 0,
+            unreported_groups: Vec::new(),
+            score_buckets: BTreeMap::new(),
+            observer: None,
+        }
     }
-}
 
-impl fmt::Display for SingleLayer {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let base = format!(
-            "SimpleDrain\nDomain Patterns: {:?}\nSimilarity Threshold: {}\n",
-            self.domain, self.threshold
-        );
-        let lg = "Log Groups:\n".to_string();
-        let groups = self
-            .iter_groups()
-            .iter()
-            .flatten()
-            .map(std::string::ToString::to_string)
-            .collect::<Vec<String>>();
-        let group_str = groups.iter().join_with("\n");
-        write!(f, "{}", [base, lg, group_str.to_string()].join_concat())
+    /// Seed the drain with a known template, returning the id of the group created for it. Words
+    /// written as `<*>` or `*` become variables and later lines which match the template join the
+    /// group. The template is bucketed like a line, so one starting with a variable is only found
+    /// by lines which route to the `*` bucket.
+    #[instrument(skip(self))]
+    pub fn add_template(&mut self, template: &str) -> Ksuid {
+        let mut group =
+            LogGroup::from_template(Record::with_options(template.to_string(), &self.tokenizer));
+        group.set_coalesce_numeric(self.coalesce_numeric);
+        group.set_example_policy(self.example_policy);
+        let id = group.get_id();
+        if let Some(o) = &self.observer {
+            o.on_new_group(id);
+        }
+        self.clear_match_cache();
+        self.insert_group(group);
+        id
     }
-}
 
-#[cfg(test)]
-mod should {
-    use spectral::prelude::*;
-    use tracing_test::traced_test;
+    /// Cluster `lines` using `threshold` in a scratch drain with this drain's configuration and
+    /// report how many groups would have been created. `self` is left untouched.
+    #[instrument(skip(self, lines))]
+    pub fn simulate_threshold(&self, lines: &[String], threshold: Ratio<BigInt>) -> usize {
+        let mut scratch = self.empty_like();
+        scratch.threshold = threshold;
+        for line in lines {
+            scratch.process_line(line.clone()).ok();
+        }
+        scratch.group_count()
+    }
 
-    use crate::drains::simple::SingleLayer;
+    /// Process `lines` until one would create a group beyond `max_groups`, returning how many
+    /// lines were consumed. The line which would have exceeded the limit is not processed, so
+    /// callers can resume from it after shedding groups or persisting the drain. Lines which fail
+    /// to process are logged and still count as consumed.
+    #[instrument(skip(self, lines))]
+    pub fn process_until_memory(
+        &mut self,
+        lines: impl Iterator<Item = String>,
+        max_groups: usize,
+    ) -> usize {
+        let mut groups = self.group_count();
+        let mut consumed = 0;
+        for line in lines {
+            if groups >= max_groups
+                && matches!(self.classify_line(line.clone()), Ok(Outcome::Unmatched))
+            {
+                break;
+            }
+            match self.process_line(line) {
+                Ok(Outcome::NewGroup(_)) => groups += 1,
+                Ok(_) => {},
+                Err(e) => warn!(%e, "failed to process line"),
+            }
+            consumed += 1;
+        }
+        consumed
+    }
 
-    #[traced_test]
-    #[test]
-    fn test_new_drain() {
-        let drain = SingleLayer::new(vec![]);
-        assert_that(&drain).is_ok();
+    /// Score lines against group templates with `similarity` instead of the built in positional
+    /// or IDF scoring. Endpoint unification doesn't apply to custom comparators. `None` restores
+    /// the built in scoring.
+    #[instrument(skip(self, similarity))]
+    pub fn set_similarity(&mut self, similarity: Option<Arc<dyn Similarity>>) {
+        self.similarity = similarity;
+        self.clear_match_cache();
     }
 
-    #[traced_test]
-    #[test]
-    fn test_set_threshold() {
-        let mut drain = SingleLayer::new(vec![]).unwrap();
-        let res = drain.set_threshold(100, 200);
-        assert_that(&res).is_ok();
+    /// Call [DrainObserver::on_report] every `interval` lines with the groups created since the
+    /// previous report, for alerting on new shapes of log line. Every line passed to
+    /// [SingleLayer::process_line] counts, including dropped ones. `None` stops reporting. The
+    /// interval is saved with the drain but observers aren't, so re-attach one with
+    /// [SingleLayer::set_observer] after restoring a drain.
+    #[instrument(skip(self))]
+    pub fn set_report_interval(&mut self, interval: Option<usize>) {
+        self.report_interval = interval.filter(|n| *n > 0);
+        self.lines_since_report = 0;
+        self.unreported_groups.clear();
     }
 
-    #[traced_test]
-    #[test]
-    fn test_single_process_line() {
-        let mut drain = SingleLayer::new(vec![]).unwrap();
-        let line_1 = "Message send failed to remote host: foo.bar.com".to_string();
-        let res = drain.process_line(line_1);
-        assert_that(&res).is_ok_containing(true);
+    /// Intern `words` up front so processing lines made of them does no further interning, which
+    /// keeps the first pass over a known vocabulary from being skewed by interner growth. Words are
+    /// folded to lower case when case folding is enabled.
+    #[instrument(skip_all)]
+    pub fn warm_interner(&mut self, words: &[&str]) {
+        let mut strings = self.strings.write();
+        for word in words {
+            if self.tokenizer.case_fold {
+                strings.get_or_intern(word.to_lowercase());
+            } else {
+                strings.get_or_intern(word);
+            }
+        }
     }
 
-    #[traced_test]
-    #[test]
-    fn test_multiple_process_line() {
-        let mut drain = SingleLayer::new(vec![]).unwrap();
-        let line_1 = "Message send failed to remote host: foo.bar.com".to_string();
-        let line_2 = "Message send failed to remote host: bork.bork.com".to_string();
-        let line_3 = "Unknown error received from peer".to_string();
-        let res = drain.process_line(line_1);
-        assert_that(&res).is_ok_containing(true);
-        let res = drain.process_line(line_2);
-        assert_that(&res).is_ok_containing(false);
-        let res = drain.process_line(line_3);
-        assert_that!(res).is_ok_containing(true);
+    /// Register an observer to be notified as groups are created and matched
+    #[instrument(skip(self, observer))]
+    pub fn set_observer(&mut self, observer: Arc<dyn DrainObserver>) {
+        self.observer = Some(observer);
     }
 
-    #[traced_test]
-    #[test]
-    fn test_iter_groups() {
-        let line_1 = "This is a sequence".to_string();
-        let line_2 = "Another different order of words".to_string();
-        let line_3 = "Finally one last unique set of character runs".to_string();
-        let mut drain = SingleLayer::new(vec![]).unwrap();
-        drain.process_line(line_1).unwrap();
-        drain.process_line(line_2).unwrap();
-        drain.process_line(line_3).unwrap();
-        let groups = drain.iter_groups();
-        assert_that(&groups).has_length(3);
+    /// Collapse runs of adjacent numeric variables into a single wildcard span in new groups
+    #[instrument(skip(self))]
+    pub fn set_coalesce_numeric(&mut self, coalesce: bool) {
+        self.coalesce_numeric = coalesce;
+    }
+
+    /// Choose which examples every group keeps, applied to existing groups straight away
+    #[instrument(skip(self))]
+    pub fn set_example_policy(&mut self, policy: ExamplePolicy) {
+        self.example_policy = policy;
+        for group in self
+            .base_layer
+            .values_mut()
+            .flat_map(HashMap::values_mut)
+            .flatten()
+        {
+            group.set_example_policy(policy);
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub fn set_threshold(&mut self, numerator: u64, denominator: u64) -> Result<(), DrainError> {
+        if denominator == 0 {
+            return Err(DrainError::InvalidThreshold {
+                numerator,
+                denominator,
+            });
+        }
+        let new_ratio = Ratio::new(BigInt::from(numerator), BigInt::from(denominator));
+        self.threshold = new_ratio;
+        self.clear_match_cache();
+        Ok(())
+    }
+
+    /// Accepts a line of input for processing against existing records
+    ///
+    /// Return
+    /// Ok(Outcome::NewGroup(id)) when a new entry is added
+    /// Ok(Outcome::Matched(id)) when the line matched an existing entry
+    /// Ok(Outcome::TooShort) when the line has fewer tokens than the configured minimum
+    /// Ok(Outcome::TooLong) when the line has more tokens than the configured maximum
+    /// Ok(Outcome::Overflow(id)) when the line was added to the catch all group
+    /// Err(e) for errors during processing
+    #[instrument(skip(self, line))]
+    pub fn process_line(&mut self, line: String) -> Result<Outcome, DrainError> {
+        self.process_scored(line).map(|(outcome, _)| outcome)
+    }
+
+    /// Process `line` like [SingleLayer::process_line], also returning the match score as a whole
+    /// percentage when the line was scored against a group
+    #[instrument(skip_all, level = "trace")]
+    fn process_scored(&mut self, line: String) -> Result<(Outcome, Option<u8>), DrainError> {
+        let outcome = self.place_line(line);
+        if let Some(interval) = self.report_interval {
+            if let Ok((Outcome::NewGroup(id), _)) = outcome {
+                self.unreported_groups.push(id);
+            }
+            self.lines_since_report += 1;
+            if self.lines_since_report >= interval {
+                self.lines_since_report = 0;
+                let new_groups = std::mem::take(&mut self.unreported_groups);
+                if let Some(o) = &self.observer {
+                    o.on_report(&new_groups);
+                }
+            }
+        }
+        outcome
+    }
+
+    /// Place `line` into a group, see [SingleLayer::process_scored]
+    #[instrument(skip_all, level = "trace")]
+    fn place_line(&mut self, line: String) -> Result<(Outcome, Option<u8>), DrainError> {
+        if line.is_empty() {
+            return Ok((Outcome::TooShort, None));
+        }
+        let new_record = match self.admit_line(line) {
+            Ok(rec) => rec,
+            Err(outcome) => return Ok((outcome, None)),
+        };
+        let uid = new_record.uid;
+        let length = new_record.len();
+        let first = self
+            .bucket_key(&new_record)
+            .expect("records have first tokens");
+        if let Some(idf) = self.idf.as_mut() {
+            idf.observe(&new_record);
+        }
+        let frozen = self.frozen;
+        let stable_after = self.stable_after;
+        let coalesce = self.coalesce_numeric;
+        let example_policy = self.example_policy;
+        let observer = self.observer.clone();
+        let new_group = |rec: Record| {
+            if let Some(o) = &observer {
+                o.on_new_group(rec.uid);
+            }
+            let mut group = LogGroup::new(rec);
+            group.set_coalesce_numeric(coalesce);
+            group.set_example_policy(example_policy);
+            group
+        };
+        let mut template_changed = false;
+        let mut score = None;
+        let cache_key = self
+            .match_cache
+            .as_ref()
+            .filter(|_| self.idf.is_none())
+            .map(|_| MatchCache::key(&new_record));
+        let cached = match (self.match_cache.as_mut(), cache_key) {
+            (Some(cache), Some(key)) => cache.get(key),
+            _ => None,
+        };
+        let cached_group = cached.and_then(|id| {
+            self.base_layer
+                .get_mut(&length)
+                .and_then(|l| l.get_mut(&first))
+                .and_then(|groups| groups.iter_mut().find(|g| g.get_id() == id))
+        });
+        let outcome = if let Some(group) = cached_group {
+            template_changed =
+                Self::accept_match(group, new_record, &observer, stable_after, false);
+            Outcome::Matched(group.get_id())
+        } else if let Some((offset, percent)) = self
+            .find_match(&new_record, length, first)
+            .or_else(|| self.find_within_mismatches(&new_record, length, first))
+        {
+            *self.score_buckets.entry(percent / 10 * 10).or_insert(0) += 1;
+            score = Some(percent);
+            let group = &mut self
+                .base_layer
+                .get_mut(&length)
+                .and_then(|l| l.get_mut(&first))
+                .expect("matched groups are in the bucket they were found in")[offset];
+            template_changed =
+                Self::accept_match(group, new_record, &observer, stable_after, false);
+            Outcome::Matched(group.get_id())
+        } else if let Some((group_length, offset, percent)) =
+            self.find_aligned_match(&new_record, first)
+        {
+            *self.score_buckets.entry(percent / 10 * 10).or_insert(0) += 1;
+            score = Some(percent);
+            let group = &mut self
+                .base_layer
+                .get_mut(&group_length)
+                .and_then(|l| l.get_mut(&first))
+                .expect("matched groups are in the bucket they were found in")[offset];
+            template_changed = Self::accept_match(group, new_record, &observer, stable_after, true);
+            Outcome::Matched(group.get_id())
+        } else if frozen {
+            Outcome::Unmatched
+        } else if self.max_groups.is_some_and(|max| self.groups_filed >= max) {
+            let catch_all = self.catch_all.get_or_insert_with(|| {
+                let mut group = LogGroup::from_template(Record::new("*".to_string()));
+                group.set_example_policy(ExamplePolicy::LastN(Self::CATCH_ALL_EXAMPLES));
+                group
+            });
+            catch_all.add_aligned_example(new_record);
+            Outcome::Overflow(catch_all.get_id())
+        } else {
+            self.base_layer
+                .entry(length)
+                .or_default()
+                .entry(first)
+                .or_default()
+                .push(new_group(new_record));
+            self.groups_filed += 1;
+            Outcome::NewGroup(uid)
+        };
+        if let (Some(cache), Some(key)) = (self.match_cache.as_mut(), cache_key) {
+            match outcome {
+                Outcome::Matched(id) if !template_changed => cache.insert(key, id),
+                Outcome::Matched(_) | Outcome::NewGroup(_) => cache.clear(),
+                Outcome::TooShort
+                | Outcome::TooLong
+                | Outcome::Unmatched
+                | Outcome::Overflow(_) => {},
+            }
+        }
+        Ok((outcome, score))
+    }
+
+    /// Report which existing group `line` would be added to without modifying the drain, as
+    /// [Outcome::Matched] with the group id or [Outcome::Unmatched] if it would start a new group.
+    /// The match cache is bypassed.
+    #[instrument(skip(self, line))]
+    pub fn classify_line(&self, line: String) -> Result<Outcome, DrainError> {
+        let rec = match self.admit_line(line) {
+            Ok(rec) => rec,
+            Err(outcome) => return Ok(outcome),
+        };
+        let length = rec.len();
+        let key = self.bucket_key(&rec).expect("records have first tokens");
+        if let Some((offset, _)) = self
+            .find_match(&rec, length, key)
+            .or_else(|| self.find_within_mismatches(&rec, length, key))
+        {
+            return Ok(Outcome::Matched(
+                self.base_layer[&length][&key][offset].get_id(),
+            ));
+        }
+        Ok(match self.find_aligned_match(&rec, key) {
+            Some((length, offset, _)) => {
+                Outcome::Matched(self.base_layer[&length][&key][offset].get_id())
+            },
+            None => Outcome::Unmatched,
+        })
+    }
+
+    /// Process every line of `reader` and write one JSON object per line to `writer` holding the
+    /// line, the outcome, the id of the group it was placed in, whether that group is new and the
+    /// match score. The score is rounded down to a whole percent and is `null` when the line
+    /// wasn't scored, such as when it started a new group or hit the match cache.
+    #[instrument(skip_all)]
+    pub fn process_reader_ndjson<R: BufRead, W: Write>(
+        &mut self,
+        reader: R,
+        mut writer: W,
+    ) -> Result<(), DrainError> {
+        for line in reader.lines() {
+            let line = line?;
+            let (outcome, score) = self.process_scored(line.clone())?;
+            let kind = match outcome {
+                Outcome::NewGroup(_) => "new_group",
+                Outcome::Matched(_) => "matched",
+                Outcome::TooShort => "too_short",
+                Outcome::TooLong => "too_long",
+                Outcome::Unmatched => "unmatched",
+                Outcome::Overflow(_) => "overflow",
+            };
+            let record = OutcomeRecord {
+                line: &line,
+                outcome: kind,
+                group_id: outcome.group_id().map(|id| id.serialize()),
+                new_group: outcome.is_new_group(),
+                score: score.map(|s| f64::from(s) / 100.0),
+            };
+            serde_json::to_writer(&mut writer, &record)?;
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Classify every line of `reader` without modifying the drain and count how many match an
+    /// existing group, measuring how well the learned templates generalize to held out data
+    #[instrument(skip(self, reader))]
+    pub fn coverage<R: BufRead>(&self, reader: R) -> Result<Coverage, DrainError> {
+        let mut coverage = Coverage::default();
+        for line in reader.lines() {
+            match self.classify_line(line?)? {
+                Outcome::Matched(_) => coverage.matched += 1,
+                Outcome::NewGroup(_) | Outcome::Unmatched | Outcome::Overflow(_) => {
+                    coverage.unmatched += 1
+                },
+                Outcome::TooShort | Outcome::TooLong => coverage.skipped += 1,
+            }
+        }
+        Ok(coverage)
+    }
+
+    /// Score `rec` against every group in its bucket and return the offset of the best scoring
+    /// group along with its score as a percentage if that score is above the threshold
+    #[instrument(skip_all, level = "trace")]
+    fn find_match(&self, rec: &Record, length: usize, key: DefaultSymbol) -> Option<(usize, u8)> {
+        let log_groups = self.base_layer.get(&length)?.get(&key)?;
+        let (matched, offset, percent) = match (self.similarity.as_ref(), self.idf.as_ref()) {
+            (Some(similarity), _) => {
+                let (score, offset) = log_groups
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, group)| (similarity.score(rec, group.event()), idx))
+                    .reduce(|best, next| if next.0 > best.0 { next } else { best })?;
+                (score > self.threshold, offset, Self::percent_of(&score))
+            },
+            (None, Some(idf)) => {
+                let (score, offset) =
+                    log_groups
+                        .iter()
+                        .enumerate()
+                        .fold((0.0, 0), |acc, (idx, group)| {
+                            let score = idf.score(rec, group.event());
+                            if score > acc.0 {
+                                (score, idx)
+                            } else {
+                                acc
+                            }
+                        });
+                let score_ratio = Ratio::from_float(score).expect("similarity scores are finite");
+                (score_ratio > self.threshold, offset, (score * 100.0) as u8)
+            },
+            (None, None) if self.position_weighting != PositionWeighting::Uniform => {
+                let weighting = self.position_weighting;
+                let (score, offset) =
+                    log_groups
+                        .iter()
+                        .enumerate()
+                        .fold((0.0, 0), |acc, (idx, group)| {
+                            let score = rec.calc_position_weighted_score(group.event(), weighting);
+                            if score > acc.0 {
+                                (score, idx)
+                            } else {
+                                acc
+                            }
+                        });
+                let score_ratio = Ratio::from_float(score).expect("similarity scores are finite");
+                (score_ratio > self.threshold, offset, (score * 100.0) as u8)
+            },
+            (None, None) => {
+                let (unify_endpoints, score_denominator) =
+                    (self.unify_endpoints, self.score_denominator);
+                let score = move |(idx, group): (usize, &LogGroup)| {
+                    let (score, denominator) =
+                        Self::positional_score(rec, group, unify_endpoints, score_denominator);
+                    (score, denominator, idx)
+                };
+                let serial = || match score_denominator {
+                    // every candidate shares the denominator, so one which can no longer beat the
+                    // best so far, or the threshold, is abandoned part way through
+                    ScoreDenominator::RecordLength if !unify_endpoints => {
+                        let floor = Self::threshold_floor(&self.threshold, length as u64);
+                        let (best, offset) = log_groups.iter().enumerate().fold(
+                            (floor, 0),
+                            |(best, offset), (idx, group)| {
+                                rec.calc_sim_score_above(group.event(), best)
+                                    .map_or((best, offset), |score| (score, idx))
+                            },
+                        );
+                        (best, length as u64, offset)
+                    },
+                    _ => log_groups
+                        .iter()
+                        .enumerate()
+                        .map(score)
+                        .fold((0, length as u64, 0), Self::better_score),
+                };
+                #[cfg(feature = "rayon")]
+                let (score, denominator, offset) = match self.parallel_scoring {
+                    Some(min_groups) if log_groups.len() >= min_groups => log_groups
+                        .par_iter()
+                        .enumerate()
+                        .map(score)
+                        .reduce(|| (0, length as u64, usize::MAX), Self::better_score),
+                    _ => serial(),
+                };
+                #[cfg(not(feature = "rayon"))]
+                let (score, denominator, offset) = serial();
+                (
+                    Self::exceeds_threshold(&self.threshold, score, denominator),
+                    offset,
+                    (score * 100 / denominator) as u8,
+                )
+            },
+        };
+        matched.then_some((offset, percent))
+    }
+
+    /// Score `rec` against `group` the way [SingleLayer::find_match] ranks candidates, as a
+    /// percentage
+    fn score_percent(&self, rec: &Record, group: &LogGroup) -> u8 {
+        match (self.similarity.as_ref(), self.idf.as_ref()) {
+            (Some(similarity), _) => Self::percent_of(&similarity.score(rec, group.event())),
+            (None, Some(idf)) => (idf.score(rec, group.event()) * 100.0) as u8,
+            (None, None) if self.position_weighting != PositionWeighting::Uniform => {
+                let score =
+                    rec.calc_position_weighted_score(group.event(), self.position_weighting);
+                (score * 100.0) as u8
+            },
+            (None, None) => {
+                let (score, denominator) = Self::positional_score(
+                    rec,
+                    group,
+                    self.unify_endpoints,
+                    self.score_denominator,
+                );
+                (score * 100 / denominator) as u8
+            },
+        }
+    }
+
+    /// Positions where `rec` agrees with `group`'s template and what that count is divided by
+    fn positional_score(
+        rec: &Record,
+        group: &LogGroup,
+        unify_endpoints: bool,
+        score_denominator: ScoreDenominator,
+    ) -> (u64, u64) {
+        let score = if unify_endpoints {
+            rec.calc_sim_score_by(group.event(), |a, b| {
+                a == b || (a.is_endpoint() && b.is_endpoint())
+            })
+        } else {
+            rec.calc_sim_score(group.event())
+        };
+        let denominator = match score_denominator {
+            ScoreDenominator::RecordLength => rec.len(),
+            ScoreDenominator::LiteralPositions => group
+                .event()
+                .into_iter()
+                .filter(|t| *t != Token::Wildcard)
+                .count()
+                .max(1),
+        };
+        (score, denominator as u64)
+    }
+
+    /// A similarity score as a whole percentage, custom comparators may stray outside 0 to 1
+    fn percent_of(score: &Ratio<BigInt>) -> u8 {
+        (score.clone() * BigInt::from(100))
+            .to_integer()
+            .clamp(BigInt::from(0), BigInt::from(100))
+            .to_u8()
+            .expect("clamped to a percentage")
+    }
+
+    /// Find the group in `rec`'s bucket whose literal positions differ from `rec` in the fewest
+    /// places, within the budget set by [SingleLayer::set_max_literal_mismatches], returning its
+    /// offset and its score as a percentage
+    #[instrument(skip_all, level = "trace")]
+    fn find_within_mismatches(
+        &self,
+        rec: &Record,
+        length: usize,
+        key: DefaultSymbol,
+    ) -> Option<(usize, u8)> {
+        let budget = self.max_literal_mismatches?;
+        let groups = self.base_layer.get(&length)?.get(&key)?;
+        let (mismatches, offset) = groups
+            .iter()
+            .enumerate()
+            .map(|(idx, group)| {
+                let mismatches = rec
+                    .into_iter()
+                    .zip(group.event().into_iter())
+                    .filter(|(token, template)| *template != Token::Wildcard && token != template)
+                    .count();
+                (mismatches, idx)
+            })
+            .min()?;
+        (mismatches <= budget).then(|| (offset, self.score_percent(rec, &groups[offset])))
+    }
+
+    /// The better of two `(score, denominator, offset)` candidates, comparing score / denominator
+    /// without dividing and preferring the lower offset on ties so any reduction order agrees
+    fn better_score(a: (u64, u64, usize), b: (u64, u64, usize)) -> (u64, u64, usize) {
+        match (u128::from(b.0) * u128::from(a.1)).cmp(&(u128::from(a.0) * u128::from(b.1))) {
+            Ordering::Greater => b,
+            Ordering::Equal if b.2 < a.2 => b,
+            _ => a,
+        }
+    }
+
+    /// Search the buckets of other lengths allowed by [SingleLayer::set_alignment_fallback] for the
+    /// group whose template shares the longest common subsequence with `rec`, returning its length,
+    /// offset and score as a percentage if that score is above the threshold
+    #[instrument(skip_all, level = "trace")]
+    fn find_aligned_match(&self, rec: &Record, key: DefaultSymbol) -> Option<(usize, usize, u8)> {
+        let max_difference = self.alignment_fallback?;
+        let length = rec.len();
+        let mut lengths = self
+            .base_layer
+            .keys()
+            .copied()
+            .filter(|l| *l != length && l.abs_diff(length) <= max_difference)
+            .collect::<Vec<usize>>();
+        // shortest first so ties are broken the same way whatever the map's order
+        lengths.sort_unstable();
+        let mut best: Option<(u64, u64, usize, usize)> = None;
+        for group_length in lengths {
+            let groups = match self.base_layer.get(&group_length).and_then(|l| l.get(&key)) {
+                Some(groups) => groups,
+                None => continue,
+            };
+            let denominator = length.max(group_length) as u64;
+            for (offset, group) in groups.iter().enumerate() {
+                let score = rec.calc_lcs_score(group.event());
+                let better = match best {
+                    Some((s, d, _, _)) => {
+                        u128::from(score) * u128::from(d) > u128::from(s) * u128::from(denominator)
+                    },
+                    None => true,
+                };
+                if better {
+                    best = Some((score, denominator, group_length, offset));
+                }
+            }
+        }
+        let (score, denominator, group_length, offset) = best?;
+        Self::exceeds_threshold(&self.threshold, score, denominator).then_some((
+            group_length,
+            offset,
+            (score * 100 / denominator) as u8,
+        ))
+    }
+
+    /// Move the drain onto a worker thread which processes every line sent through the returned
+    /// [Sender]. Once every clone of the sender has been dropped the worker hands the drain back
+    /// through the [JoinHandle].
+    #[instrument(skip(self))]
+    pub fn spawn_worker(mut self) -> (Sender<String>, JoinHandle<SingleLayer>) {
+        let (tx, rx) = mpsc::channel::<String>();
+        let handle = thread::spawn(move || {
+            for line in rx {
+                if let Err(e) = self.process_line(line) {
+                    warn!(%e, "worker failed to process line");
+                }
+            }
+            self
+        });
+        (tx, handle)
+    }
+
+    /// Whether `score / length` is above `threshold`. Compares in `u128` when the threshold fits
+    /// in `u64` to avoid building a [BigInt] ratio for every candidate, falling back to [Ratio]
+    /// otherwise.
+    #[instrument(level = "trace")]
+    fn exceeds_threshold(threshold: &Ratio<BigInt>, score: u64, length: u64) -> bool {
+        match (threshold.numer().to_u64(), threshold.denom().to_u64()) {
+            (Some(numer), Some(denom)) => {
+                u128::from(score) * u128::from(denom) > u128::from(numer) * u128::from(length)
+            },
+            _ => Ratio::new(BigInt::from(score), BigInt::from(length)) > *threshold,
+        }
+    }
+
+    /// The highest score out of `length` which doesn't exceed `threshold`
+    fn threshold_floor(threshold: &Ratio<BigInt>, length: u64) -> u64 {
+        (threshold.clone() * BigInt::from(length))
+            .floor()
+            .to_integer()
+            .to_u64()
+            .unwrap_or(0)
+    }
+
+    /// Add `rec` as an example of `group` and notify the observer, returning whether the group's
+    /// template changed as a result
+    #[instrument(skip_all, level = "trace")]
+    fn accept_match(
+        group: &mut LogGroup,
+        rec: Record,
+        observer: &Option<Arc<dyn DrainObserver>>,
+        stable_after: Option<usize>,
+        aligned: bool,
+    ) -> bool {
+        let wildcards = group.variables.len();
+        // add this record's uid to the list of examples for the log group
+        if aligned {
+            group.add_aligned_example(rec);
+        } else {
+            group.add_example(rec);
+        }
+        let id = group.get_id();
+        if let Some(o) = observer {
+            o.on_match(id);
+            if stable_after == Some(group.stable_count()) {
+                o.on_stable(id);
+            }
+        }
+        group.variables.len() != wildcards
+    }
+
+    #[instrument(skip(self), level = "trace")]
+    pub fn iter_groups(&self) -> Vec<Vec<&LogGroup>> {
+        let mut results: Vec<Vec<&LogGroup>> = Vec::new();
+        for length in self.base_layer.keys() {
+            let mut groups = vec![];
+            for (_, grp) in self.base_layer.get(length).unwrap().iter() {
+                for g in grp {
+                    groups.push(g);
+                }
+            }
+            results.push(groups);
+        }
+        results
+    }
+
+    /// Distinct rendered templates with the total number of lines matched by every group sharing
+    /// that template, ordered by count descending and then by template
+    #[instrument(skip(self), level = "trace")]
+    pub fn distinct_templates(&self) -> Vec<(String, u64)> {
+        let mut counts: HashMap<String, u64> = HashMap::new();
+        for group in self.iter_groups().into_iter().flatten() {
+            *counts.entry(group.template()).or_insert(0) += group.count();
+        }
+        let mut templates = counts.into_iter().collect::<Vec<(String, u64)>>();
+        templates.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        templates
+    }
+
+    /// The `n` groups which have absorbed the most lines, ordered by count descending. Groups with
+    /// equal counts are ordered oldest first.
+    #[instrument(skip(self), level = "trace")]
+    pub fn top_groups(&self, n: usize) -> Vec<&LogGroup> {
+        let mut groups = self
+            .iter_groups()
+            .into_iter()
+            .flatten()
+            .collect::<Vec<&LogGroup>>();
+        groups.sort_by(|a, b| {
+            b.count()
+                .cmp(&a.count())
+                .then_with(|| a.sequence().cmp(&b.sequence()))
+        });
+        groups.truncate(n);
+        groups
+    }
+
+    /// Groups whose rendered template matches the regular expression `pattern`, oldest first
+    #[instrument(skip(self))]
+    pub fn find_groups(&self, pattern: &str) -> Result<Vec<&LogGroup>, DrainError> {
+        let re = Regex::new(pattern)?;
+        let mut groups = self
+            .iter_groups()
+            .into_iter()
+            .flatten()
+            .filter(|g| re.is_match(&g.template()))
+            .collect::<Vec<&LogGroup>>();
+        groups.sort_by_key(|g| g.sequence());
+        Ok(groups)
+    }
+
+    /// Compare the rendered templates of this drain with `other`'s, for spotting log shapes which
+    /// only occur in one environment
+    #[instrument(skip_all)]
+    pub fn template_diff(&self, other: &SingleLayer) -> TemplateDiff {
+        let templates = |drain: &SingleLayer| {
+            drain
+                .iter_groups()
+                .into_iter()
+                .flatten()
+                .map(LogGroup::template)
+                .collect::<BTreeSet<String>>()
+        };
+        let ours = templates(self);
+        let theirs = templates(other);
+        TemplateDiff {
+            only_self: ours.difference(&theirs).cloned().collect(),
+            only_other: theirs.difference(&ours).cloned().collect(),
+            common: ours.intersection(&theirs).cloned().collect(),
+        }
+    }
+
+    /// Capture the current template of every group, compare two captures with [Snapshot::diff]
+    /// to find log shapes which appeared, disappeared or were generalized in between
+    #[instrument(skip(self))]
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            templates: self
+                .iter_groups()
+                .into_iter()
+                .flatten()
+                .map(|g| (g.get_id().serialize(), g.template()))
+                .collect(),
+        }
+    }
+
+    /// Rough clustering quality between 0 and 1, the similarity of every stored example to the
+    /// line which created its group averaged across all examples. Lines which barely cleared the
+    /// threshold drag it down. Returns 0 until a line has matched an existing group.
+    #[instrument(skip(self))]
+    pub fn quality(&self) -> f64 {
+        let (total, examples) = self
+            .base_layer
+            .values()
+            .flat_map(HashMap::values)
+            .flatten()
+            .flat_map(|group| {
+                let base = group.origin().unwrap_or_else(|| group.event());
+                group
+                    .get_examples()
+                    .into_iter()
+                    .map(move |ex| ex.calc_sim_score(base) as f64 / base.len() as f64)
+            })
+            .fold((0.0, 0), |(total, n), score| (total + score, n + 1));
+        if examples == 0 {
+            0.0
+        } else {
+            total / examples as f64
+        }
+    }
+
+    /// Number of matched lines by score, keyed on the score as a percentage rounded down to a
+    /// multiple of 10. Many matches just above the threshold suggest it is too permissive. Lines
+    /// matched through the match cache aren't rescored so they aren't counted.
+    #[instrument(skip(self), level = "trace")]
+    pub fn score_distribution(&self) -> BTreeMap<u8, usize> {
+        self.score_buckets.clone()
+    }
+
+    /// Total number of log groups across every bucket
+    #[instrument(skip(self), level = "trace")]
+    pub fn group_count(&self) -> usize {
+        self.base_layer
+            .values()
+            .flat_map(HashMap::values)
+            .map(Vec::len)
+            .sum()
+    }
+
+    /// Consume the drain and reconstruct every line it has retained, so they can be fed into a
+    /// drain with a different configuration. Groups, including the catch all group of
+    /// [SingleLayer::set_max_groups], are emitted oldest first, each starting with the line which
+    /// created it followed by its examples in the order they arrived. Only every absorbed line
+    /// comes back under [ExamplePolicy::All], other policies and the catch all group drop
+    /// examples which can't be replayed.
+    #[instrument(skip(self))]
+    pub fn replay(self) -> Vec<String> {
+        let mut groups = self
+            .base_layer
+            .into_values()
+            .flat_map(HashMap::into_values)
+            .flatten()
+            .chain(self.catch_all)
+            .collect::<Vec<LogGroup>>();
+        groups.sort_by_key(LogGroup::sequence);
+        groups
+            .iter()
+            .flat_map(|g| g.origin().into_iter().chain(g.get_examples()))
+            .map(Record::to_string)
+            .collect()
+    }
+
+    /// Number of log groups at each token length, ordered by length
+    #[instrument(skip(self), level = "trace")]
+    pub fn length_histogram(&self) -> BTreeMap<usize, usize> {
+        self.base_layer
+            .iter()
+            .map(|(length, buckets)| (*length, buckets.values().map(Vec::len).sum()))
+            .filter(|(_, groups)| *groups > 0)
+            .collect()
+    }
+
+    /// Encode the drain in a compact binary form using bincode with variable length integers
+    #[cfg(feature = "bincode")]
+    #[instrument(skip(self))]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DrainError> {
+        Ok(bincode::DefaultOptions::new().serialize(self)?)
+    }
+
+    /// Rebuild a drain from the output of [SingleLayer::to_bytes]
+    #[cfg(feature = "bincode")]
+    #[instrument(skip(bytes))]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DrainError> {
+        Ok(bincode::DefaultOptions::new().deserialize(bytes)?)
+    }
+
+    /// Process every line of `reader`, appending a checkpoint of the drain to `out` after every
+    /// `every` lines and once more at the end of the input. Each checkpoint is the output of
+    /// [SingleLayer::to_bytes] preceded by its length as a little endian `u64`, and `out` is
+    /// flushed after each one. An `every` of zero only checkpoints at the end. Recover with
+    /// [SingleLayer::last_checkpoint].
+    ///
+    /// Checkpoints are appended, never replaced, so `out` grows by a full snapshot of the drain
+    /// every `every` lines. Only the last one is needed for recovery, so truncate or rotate `out`
+    /// between runs.
+    #[cfg(feature = "bincode")]
+    #[instrument(skip(self, reader, out))]
+    pub fn process_reader_with_checkpoint<R: BufRead, W: Write>(
+        &mut self,
+        reader: R,
+        every: usize,
+        mut out: W,
+    ) -> Result<(), DrainError> {
+        let mut since_checkpoint = 0;
+        for line in reader.lines() {
+            self.process_line(line?)?;
+            since_checkpoint += 1;
+            if since_checkpoint == every {
+                self.write_checkpoint(&mut out)?;
+                since_checkpoint = 0;
+            }
+        }
+        if since_checkpoint > 0 {
+            self.write_checkpoint(&mut out)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "bincode")]
+    fn write_checkpoint<W: Write>(&self, out: &mut W) -> Result<(), DrainError> {
+        let bytes = self.to_bytes()?;
+        out.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        out.write_all(&bytes)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Rebuild the drain from the last complete checkpoint written by
+    /// [SingleLayer::process_reader_with_checkpoint], or `None` if there isn't one. A checkpoint
+    /// cut short by a crash while it was being written is ignored.
+    #[cfg(feature = "bincode")]
+    #[instrument(skip(reader))]
+    pub fn last_checkpoint<R: Read>(mut reader: R) -> Result<Option<Self>, DrainError> {
+        let mut last = None;
+        loop {
+            let mut len = [0u8; 8];
+            match reader.read_exact(&mut len) {
+                Ok(()) => {},
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            let len = u64::from_le_bytes(len);
+            // the prefix of a torn write can be garbage, so only allocate for bytes actually read
+            let mut frame = Vec::new();
+            if reader.by_ref().take(len).read_to_end(&mut frame)? as u64 != len {
+                break;
+            }
+            last = Some(frame);
+        }
+        last.map(|bytes| Self::from_bytes(&bytes)).transpose()
+    }
+
+    /// The full bucket structure as pretty printed JSON for debugging. Each bucket lists its token
+    /// count, its key and its groups with their id, template, count, variable positions and number
+    /// of stored examples. Buckets are ordered by token count and then key.
+    #[instrument(skip(self))]
+    pub fn debug_dump(&self) -> String {
+        let mut buckets = self
+            .base_layer
+            .iter()
+            .flat_map(|(length, keyed)| {
+                keyed
+                    .iter()
+                    .filter(|(_, groups)| !groups.is_empty())
+                    .map(move |(key, groups)| (*length, self.resolve(*key), groups))
+            })
+            .collect::<Vec<(usize, String, &Vec<LogGroup>)>>();
+        buckets.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+        let dump = DrainDump {
+            buckets: buckets
+                .into_iter()
+                .map(|(length, key, groups)| BucketDump {
+                    length,
+                    key,
+                    groups: groups.iter().map(GroupDump::from).collect(),
+                })
+                .collect(),
+        };
+        serde_json::to_string_pretty(&dump).expect("debug dumps serialize to JSON")
+    }
+
+    /// Every interned symbol with its string in symbol order, for diagnosing symbol bloat. The
+    /// interner is currently shared by all drains so this includes words seen by any of them.
+    #[instrument(skip(self))]
+    pub fn dump_interner(&self) -> Vec<(DefaultSymbol, String)> {
+        self.strings
+            .read()
+            .into_iter()
+            .map(|(sym, word)| (sym, word.to_owned()))
+            .collect()
+    }
+
+    #[instrument(skip(self), level = "trace")]
+    pub fn resolve(&self, sym: DefaultSymbol) -> String {
+        self.strings
+            .read()
+            .resolve(sym)
+            .expect("symbols must resolve")
+            .to_owned()
+    }
+
+    /// Like [SingleLayer::resolve] but borrows the string from the interner instead of copying it.
+    /// The guard holds the interner's read lock, so drop it before processing more lines or
+    /// they'll wait on it.
+    #[instrument(skip(self), level = "trace")]
+    pub fn resolve_ref(&self, sym: DefaultSymbol) -> MappedRwLockReadGuard<'_, str> {
+        RwLockReadGuard::map(self.strings.read(), |strings| {
+            strings.resolve(sym).expect("symbols must resolve")
+        })
+    }
+}
+
+impl Drain for SingleLayer {
+    fn process_line(&mut self, line: String) -> Result<Outcome, DrainError> {
+        SingleLayer::process_line(self, line)
+    }
+
+    fn classify_line(&self, line: String) -> Result<Outcome, DrainError> {
+        SingleLayer::classify_line(self, line)
+    }
+
+    fn iter_groups(&self) -> Vec<Vec<&LogGroup>> {
+        SingleLayer::iter_groups(self)
+    }
+
+    fn set_threshold(&mut self, numerator: u64, denominator: u64) -> Result<(), DrainError> {
+        SingleLayer::set_threshold(self, numerator, denominator)
+    }
+}
+
+/// [SingleLayer::debug_dump] output
+#[derive(Serialize)]
+struct DrainDump {
+    buckets: Vec<BucketDump>,
+}
+
+#[derive(Serialize)]
+struct BucketDump {
+    length: usize,
+    key: String,
+    groups: Vec<GroupDump>,
+}
+
+#[derive(Serialize)]
+struct GroupDump {
+    id: String,
+    template: String,
+    count: u64,
+    variables: Vec<usize>,
+    examples: usize,
+}
+
+impl From<&LogGroup> for GroupDump {
+    fn from(group: &LogGroup) -> Self {
+        Self {
+            id: group.get_id().serialize(),
+            template: group.template(),
+            count: group.count(),
+            variables: group.variable_positions(),
+            examples: group.len(),
+        }
+    }
+}
+
+/// One line of [SingleLayer::process_reader_ndjson] output
+#[derive(Serialize)]
+struct OutcomeRecord<'a> {
+    line: &'a str,
+    outcome: &'static str,
+    group_id: Option<String>,
+    new_group: bool,
+    score: Option<f64>,
+}
+
+/// Borrowed view of a [SingleLayer] used for serialization, groups are stored as a flat list
+/// and rebucketed when the drain is deserialized
+#[derive(Serialize)]
+struct DrainStateRef<'a> {
+    domain: Vec<&'a str>,
+    threshold: (String, String),
+    tokenizer: TokenizerOptions,
+    prefix_depth: Option<usize>,
+    fuzzy_first_token: bool,
+    coalesce_numeric: bool,
+    example_policy: ExamplePolicy,
+    min_tokens: usize,
+    frozen: bool,
+    stable_after: Option<usize>,
+    idf: Option<&'a DocumentFrequency>,
+    unify_endpoints: bool,
+    max_tokens: Option<(usize, LongLinePolicy)>,
+    position_weighting: PositionWeighting,
+    score_denominator: ScoreDenominator,
+    alignment_fallback: Option<usize>,
+    max_literal_mismatches: Option<usize>,
+    match_cache: Option<usize>,
+    report_interval: Option<usize>,
+    parallel_scoring: Option<usize>,
+    groups: Vec<&'a LogGroup>,
+    max_groups: Option<usize>,
+    catch_all: Option<&'a LogGroup>,
+}
+
+#[derive(Deserialize)]
+struct DrainState {
+    domain: Vec<String>,
+    threshold: (String, String),
+    #[serde(default)]
+    tokenizer: TokenizerOptions,
+    #[serde(default)]
+    prefix_depth: Option<usize>,
+    #[serde(default)]
+    fuzzy_first_token: bool,
+    #[serde(default)]
+    coalesce_numeric: bool,
+    #[serde(default)]
+    example_policy: ExamplePolicy,
+    #[serde(default)]
+    min_tokens: usize,
+    #[serde(default)]
+    frozen: bool,
+    #[serde(default)]
+    stable_after: Option<usize>,
+    #[serde(default)]
+    idf: Option<DocumentFrequency>,
+    #[serde(default)]
+    unify_endpoints: bool,
+    #[serde(default)]
+    max_tokens: Option<(usize, LongLinePolicy)>,
+    #[serde(default)]
+    position_weighting: PositionWeighting,
+    #[serde(default)]
+    score_denominator: ScoreDenominator,
+    #[serde(default)]
+    alignment_fallback: Option<usize>,
+    #[serde(default)]
+    max_literal_mismatches: Option<usize>,
+    #[serde(default)]
+    match_cache: Option<usize>,
+    #[serde(default)]
+    report_interval: Option<usize>,
+    // Kept without the rayon feature so states load in either build
+    #[serde(default)]
+    #[cfg_attr(not(feature = "rayon"), allow(dead_code))]
+    parallel_scoring: Option<usize>,
+    groups: Vec<LogGroup>,
+    #[serde(default)]
+    max_groups: Option<usize>,
+    #[serde(default)]
+    catch_all: Option<LogGroup>,
+}
+
+impl Serialize for SingleLayer {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        DrainStateRef {
+            domain: self.domain.iter().map(Regex::as_str).collect(),
+            threshold: (
+                self.threshold.numer().to_string(),
+                self.threshold.denom().to_string(),
+            ),
+            tokenizer: self.tokenizer.clone(),
+            prefix_depth: self.prefix_depth,
+            fuzzy_first_token: self.fuzzy_first_token,
+            coalesce_numeric: self.coalesce_numeric,
+            example_policy: self.example_policy,
+            min_tokens: self.min_tokens,
+            frozen: self.frozen,
+            stable_after: self.stable_after,
+            idf: self.idf.as_ref(),
+            unify_endpoints: self.unify_endpoints,
+            max_tokens: self.max_tokens,
+            position_weighting: self.position_weighting,
+            score_denominator: self.score_denominator,
+            alignment_fallback: self.alignment_fallback,
+            max_literal_mismatches: self.max_literal_mismatches,
+            match_cache: self.match_cache.as_ref().map(MatchCache::capacity),
+            report_interval: self.report_interval,
+            #[cfg(feature = "rayon")]
+            parallel_scoring: self.parallel_scoring,
+            #[cfg(not(feature = "rayon"))]
+            parallel_scoring: None,
+            groups: self.iter_groups().into_iter().flatten().collect(),
+            max_groups: self.max_groups,
+            catch_all: self.catch_all.as_ref(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SingleLayer {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error as _;
+        let state = DrainState::deserialize(deserializer)?;
+        let mut drain = SingleLayer::new(state.domain).map_err(D::Error::custom)?;
+        let numer = BigInt::from_str(&state.threshold.0).map_err(D::Error::custom)?;
+        let denom = BigInt::from_str(&state.threshold.1).map_err(D::Error::custom)?;
+        drain.threshold = Ratio::new(numer, denom);
+        drain.tokenizer = state.tokenizer;
+        drain.prefix_depth = state.prefix_depth;
+        drain.fuzzy_first_token = state.fuzzy_first_token;
+        drain.coalesce_numeric = state.coalesce_numeric;
+        drain.example_policy = state.example_policy;
+        drain.min_tokens = state.min_tokens;
+        drain.frozen = state.frozen;
+        drain.stable_after = state.stable_after;
+        drain.idf = state.idf;
+        drain.unify_endpoints = state.unify_endpoints;
+        drain.max_tokens = state.max_tokens;
+        drain.position_weighting = state.position_weighting;
+        drain.score_denominator = state.score_denominator;
+        drain.alignment_fallback = state.alignment_fallback;
+        drain.max_literal_mismatches = state.max_literal_mismatches;
+        drain.match_cache = state.match_cache.map(MatchCache::new);
+        drain.report_interval = state.report_interval.filter(|n| *n > 0);
+        #[cfg(feature = "rayon")]
+        {
+            drain.parallel_scoring = state.parallel_scoring;
+        }
+        drain.max_groups = state.max_groups;
+        drain.catch_all = state.catch_all;
+        for group in state.groups {
+            if group.event().is_empty() {
+                return Err(D::Error::custom("log group with an empty event"));
+            }
+            drain.insert_group(group);
+        }
+        Ok(drain)
+    }
+}
+
+impl fmt::Display for SingleLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let base = format!(
+            "SimpleDrain\nDomain Patterns: {:?}\nSimilarity Threshold: {}\n",
+            self.domain, self.threshold
+        );
+        let lg = "Log Groups:\n".to_string();
+        let groups = self
+            .iter_groups()
+            .iter()
+            .flatten()
+            .map(std::string::ToString::to_string)
+            .collect::<Vec<String>>();
+        let group_str = groups.iter().join_with("\n");
+        write!(f, "{}", [base, lg, group_str.to_string()].join_concat())
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        io::Cursor,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use fraction::{BigInt, Ratio};
+    use regex::Regex;
+    use rksuid::Ksuid;
+    use spectral::prelude::*;
+    use string_interner::DefaultSymbol;
+    use tracing_test::traced_test;
+
+    use crate::{
+        drains::{
+            observer::DrainObserver,
+            similarity::Similarity,
+            simple::{
+                Coverage, DomainPreset, LongLinePolicy, Outcome, ScoreDenominator, SingleLayer,
+                INTERNER,
+            },
+            Drain,
+        },
+        error::DrainError,
+        log_group::{ExamplePolicy, LogGroup},
+        record::{PositionWeighting, Record},
+    };
+
+    #[derive(Debug, Default)]
+    struct CountingObserver {
+        new_groups: AtomicUsize,
+        matches: AtomicUsize,
+        stable: AtomicUsize,
+    }
+
+    impl DrainObserver for CountingObserver {
+        fn on_new_group(&self, _group_id: Ksuid) {
+            self.new_groups.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_match(&self, _group_id: Ksuid) {
+            self.matches.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_stable(&self, _group_id: Ksuid) {
+            self.stable.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_new_drain() {
+        let drain = SingleLayer::new(vec![]);
+        assert_that(&drain).is_ok();
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_set_threshold() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        let res = drain.set_threshold(100, 200);
+        assert_that(&res).is_ok();
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_single_process_line() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        let line_1 = "Message send failed to remote host: foo.bar.com".to_string();
+        let res = drain.process_line(line_1);
+        assert_that(&res.unwrap().is_new_group()).is_true();
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_multiple_process_line() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        let line_1 = "Message send failed to remote host: foo.bar.com".to_string();
+        let line_2 = "Message send failed to remote host: bork.bork.com".to_string();
+        let line_3 = "Unknown error received from peer".to_string();
+        let res = drain.process_line(line_1);
+        let first = res.unwrap();
+        assert_that(&first.is_new_group()).is_true();
+        let res = drain.process_line(line_2);
+        assert_that(&res).is_ok_containing(Outcome::Matched(first.group_id().unwrap()));
+        let res = drain.process_line(line_3);
+        assert_that(&res.unwrap().is_new_group()).is_true();
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_iter_groups() {
+        let line_1 = "This is a sequence".to_string();
+        let line_2 = "Another different order of words".to_string();
+        let line_3 = "Finally one last unique set of character runs".to_string();
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.process_line(line_1).unwrap();
+        drain.process_line(line_2).unwrap();
+        drain.process_line(line_3).unwrap();
+        let groups = drain.iter_groups();
+        assert_that(&groups).has_length(3);
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_min_tokens() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.set_min_tokens(3);
+        let res = drain.process_line("Connection reset".to_string());
+        assert_that(&res).is_ok_containing(Outcome::TooShort);
+        assert_that(&drain.group_count()).is_equal_to(0);
+        let res = drain.process_line("Connection reset by peer".to_string());
+        assert_that(&res.unwrap().is_new_group()).is_true();
+        let res = drain.process_line("   ".to_string());
+        assert_that(&res).is_ok_containing(Outcome::TooShort);
+    }
+
+    #[test]
+    fn test_max_tokens() {
+        let line = (0..10_000)
+            .map(|i| format!("t{}", i))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.set_max_tokens(Some(100), LongLinePolicy::Reject);
+        let res = drain.process_line(line.clone());
+        assert_that(&res).is_ok_containing(Outcome::TooLong);
+        assert_that(&drain.group_count()).is_equal_to(0);
+        drain.set_max_tokens(Some(100), LongLinePolicy::Truncate);
+        assert_that(&drain.process_line(line).unwrap().is_new_group()).is_true();
+        let groups = drain.iter_groups();
+        let event = groups[0][0].event();
+        assert_that(&event.len()).is_equal_to(100);
+        assert_that(&event.to_string().ends_with("t98 ...")).is_true();
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_frozen_drain() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain
+            .process_line("Message send failed to remote host: foo.bar.com".to_string())
+            .unwrap();
+        drain.freeze();
+        let res =
+            drain.process_line("Message send failed to remote host: bork.bork.com".to_string());
+        assert_that(&res.unwrap()).matches(|o| matches!(o, Outcome::Matched(_)));
+        for novel in [
+            "Unknown error received from peer",
+            "Message dropped by remote host: foo.bar.com",
+            "Message",
+        ] {
+            let res = drain.process_line(novel.to_string());
+            assert_that(&res).is_ok_containing(Outcome::Unmatched);
+        }
+        assert_that(&drain.group_count()).is_equal_to(1);
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_match_cache() {
+        let lines = [
+            "Message send failed to remote host: foo.bar.com",
+            "Message send failed to remote host: bork.bork.com",
+            "Unknown error received from peer",
+        ];
+        let mut plain = SingleLayer::new(vec![]).unwrap();
+        let mut cached = SingleLayer::new(vec![]).unwrap();
+        cached.set_match_cache(Some(16));
+        for _ in 0..5 {
+            for line in lines {
+                let expected = plain.process_line(line.to_string()).unwrap();
+                let actual = cached.process_line(line.to_string()).unwrap();
+                assert_that(&actual.is_new_group()).is_equal_to(expected.is_new_group());
+            }
+        }
+        assert_that(&cached.group_count()).is_equal_to(plain.group_count());
+        assert_that(&cached.match_cache_hits()).is_greater_than(0);
+        assert_that(&plain.match_cache_hits()).is_equal_to(0);
+    }
+
+    #[test]
+    fn test_match_cache_follows_scoring_settings() {
+        let line = "Message send failed to remote host: bork.bork.com";
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.set_match_cache(Some(16));
+        for l in [
+            "Message send failed to remote host: foo.bar.com",
+            line,
+            line,
+        ] {
+            drain.process_line(l.to_string()).unwrap();
+        }
+        drain.set_threshold(99, 100).unwrap();
+        let outcome = drain.process_line(line.to_string()).unwrap();
+        assert_that!(outcome.is_new_group()).is_true();
+        // Token weights change with every line so IDF scoring never uses the cache
+        drain.set_idf_scoring(true);
+        let hits = drain.match_cache_hits();
+        for _ in 0..3 {
+            drain.process_line(line.to_string()).unwrap();
+        }
+        assert_that!(drain.match_cache_hits()).is_equal_to(hits);
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_distinct_templates() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain
+            .process_line("Unknown error received from peer".to_string())
+            .unwrap();
+        // Two groups in the same bucket which generalized to the same template
+        let mut groups = vec![];
+        for (base, example) in [("sda1", "sdb1"), ("sdc1", "sdd1")] {
+            let mut group = LogGroup::new(Record::new(format!("Disk {} is full", base)));
+            group.add_example(Record::new(format!("Disk {} is full", example)));
+            groups.push(group);
+        }
+        groups[0].add_example(Record::new("Disk sde1 is full".to_string()));
+        let first = groups[0].event().first().unwrap();
+        drain.base_layer.entry(4).or_default().insert(first, groups);
+
+        let templates = drain.distinct_templates();
+        assert_that(&templates).is_equal_to(vec![
+            ("Disk * is full".to_string(), 5),
+            ("Unknown error received from peer".to_string(), 1),
+        ]);
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_idf_scoring() {
+        let mut plain = SingleLayer::new(vec![]).unwrap();
+        let mut weighted = SingleLayer::new(vec![]).unwrap();
+        weighted.set_idf_scoring(true);
+        for i in 0..20 {
+            let line = format!("the request failed for user{}", i);
+            plain.process_line(line.clone()).unwrap();
+            weighted.process_line(line).unwrap();
+        }
+        // only shares the common words with the existing group
+        let line = "the quota failed for alice".to_string();
+        assert!(!plain.process_line(line.clone()).unwrap().is_new_group());
+        assert!(weighted.process_line(line).unwrap().is_new_group());
+    }
+
+    #[test]
+    fn test_spawn_worker() {
+        let drain = SingleLayer::new(vec![]).unwrap();
+        let (tx, handle) = drain.spawn_worker();
+        let producers = (0..4)
+            .map(|p| {
+                let tx = tx.clone();
+                std::thread::spawn(move || {
+                    for i in 0..10 {
+                        tx.send(format!("Worker {} finished job {}", p, i)).unwrap();
+                        tx.send(format!("Connection to db{} lost", i)).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(tx);
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let drain = handle.join().unwrap();
+        assert_that!(drain.group_count()).is_equal_to(2);
+    }
+
+    #[test]
+    fn test_prefix_depth() {
+        let lines = (0..10)
+            .map(|i| format!("10:00:{:02} worker pool started job {}", i, i * 7))
+            .collect::<Vec<String>>();
+        let mut first_token = SingleLayer::new(vec![]).unwrap();
+        let mut prefix = SingleLayer::new(vec![]).unwrap();
+        prefix.set_prefix_depth(Some(3));
+        for line in &lines {
+            first_token.process_line(line.clone()).unwrap();
+            prefix.process_line(line.clone()).unwrap();
+        }
+        assert_that!(first_token.group_count()).is_equal_to(10);
+        assert_that!(prefix.group_count()).is_equal_to(1);
+        // lines routed down a different prefix don't share a bucket
+        let res = prefix.process_line("10:00:11 worker queue started job 77".to_string());
+        assert!(res.unwrap().is_new_group());
+    }
+
+    #[test]
+    fn test_fuzzy_first_token() {
+        let lines = [
+            "1234 connection reset by peer",
+            "5678 connection reset by peer",
+        ];
+        let mut exact = SingleLayer::new(vec![]).unwrap();
+        let mut fuzzy = SingleLayer::new(vec![]).unwrap();
+        fuzzy.set_fuzzy_first_token(true);
+        for line in lines {
+            exact.process_line(line.to_string()).unwrap();
+            fuzzy.process_line(line.to_string()).unwrap();
+        }
+        assert_that!(exact.group_count()).is_equal_to(2);
+        assert_that!(fuzzy.group_count()).is_equal_to(1);
+        assert_that!(fuzzy.distinct_templates()[0].0.as_str())
+            .is_equal_to("* connection reset by peer");
+        // keywords still tell lines apart when the rest of the line would let them merge
+        let mut methods = SingleLayer::new(vec![]).unwrap();
+        methods.set_fuzzy_first_token(true);
+        methods.set_threshold(1, 10).unwrap();
+        for line in ["GET /a", "POST /a"] {
+            methods.process_line(line.to_string()).unwrap();
+        }
+        assert_that!(methods.group_count()).is_equal_to(2);
+    }
+
+    #[test]
+    fn test_unify_endpoints() {
+        let lines = [
+            "upstream api.example.com refused the connection",
+            "upstream 10.0.0.5 refused the connection",
+        ];
+        let mut strict = SingleLayer::new(vec![]).unwrap();
+        let mut unified = SingleLayer::new(vec![]).unwrap();
+        unified.set_unify_endpoints(true);
+        for drain in [&mut strict, &mut unified] {
+            drain.set_threshold(9, 10).unwrap();
+            for line in lines {
+                drain.process_line(line.to_string()).unwrap();
+            }
+        }
+        assert_that!(strict.group_count()).is_equal_to(2);
+        assert_that!(unified.group_count()).is_equal_to(1);
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        for i in 0..5 {
+            drain
+                .process_line(format!(
+                    "Connection to db{} lost after {} ms",
+                    i % 2,
+                    i * 10
+                ))
+                .unwrap();
+            drain
+                .process_line(format!("User u{} logged in", i))
+                .unwrap();
+        }
+        let expected = drain.distinct_templates();
+        let lines = drain.replay();
+        assert_that!(lines).has_length(10);
+        let mut replayed = SingleLayer::new(vec![]).unwrap();
+        for line in lines {
+            replayed.process_line(line).unwrap();
+        }
+        assert_that!(replayed.distinct_templates()).is_equal_to(expected);
+    }
+
+    #[test]
+    fn test_replay_retained() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.set_example_policy(ExamplePolicy::FirstN(1));
+        drain.set_max_groups(Some(1));
+        for line in [
+            "Disk sda1 is full",
+            "Disk sdb1 is full",
+            "Disk sdc1 is full",
+            "Kernel panic",
+            "Fan speed critical",
+        ] {
+            drain.process_line(line.to_string()).unwrap();
+        }
+        assert_that!(drain.replay()).is_equal_to(vec![
+            "Disk sda1 is full".to_string(),
+            "Disk sdb1 is full".to_string(),
+            "Kernel panic".to_string(),
+            "Fan speed critical".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_split_pid() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.set_split_pid(true);
+        for pid in [4567, 99, 31337] {
+            drain
+                .process_line(format!("sshd[{}]: Accepted publickey for root", pid))
+                .unwrap();
+        }
+        assert_that!(drain.distinct_templates()).is_equal_to(vec![(
+            "sshd[*]: Accepted publickey for root".to_string(),
+            3,
+        )]);
+    }
+
+    #[test]
+    fn test_process_until_memory() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        let lines = [
+            "Disk sda1 is 91% full",
+            "Fan speed set to 1200 rpm",
+            "Disk sdb2 is 91% full",
+            "User alice logged in",
+            "Fan speed set to 900 rpm",
+        ];
+        let consumed = drain.process_until_memory(lines.iter().map(|l| l.to_string()), 2);
+        // the fourth line would start a third group
+        assert_that!(consumed).is_equal_to(3);
+        assert_that!(drain.group_count()).is_equal_to(2);
+        let consumed = drain.process_until_memory(lines[3..].iter().map(|l| l.to_string()), 3);
+        assert_that!(consumed).is_equal_to(2);
+        assert_that!(drain.group_count()).is_equal_to(3);
+    }
+
+    #[test]
+    fn test_score_distribution() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        for line in [
+            "Disk sda1 is 91% full on host01",
+            "Disk sda1 is 91% full on host01",
+            "Disk sdb2 is 91% full on host01",
+            "Disk sdc3 is 97% full on host02",
+        ] {
+            drain.process_line(line.to_string()).unwrap();
+        }
+        // 7/7, 6/7 and 4/7 of the tokens matched
+        assert_that!(drain.score_distribution()).is_equal_to(BTreeMap::from([
+            (50, 1),
+            (80, 1),
+            (100, 1),
+        ]));
+    }
+
+    #[test]
+    fn test_add_template() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        let id = drain.add_template("Disk <*> is * full");
+        let res = drain.process_line("Disk sdc3 is 97% full".to_string());
+        assert_that!(res.unwrap()).is_equal_to(Outcome::Matched(id));
+        assert_that!(drain.distinct_templates())
+            .is_equal_to(vec![("Disk * is * full".to_string(), 1)]);
+    }
+
+    #[test]
+    fn test_quality() {
+        let mut similar = SingleLayer::new(vec![]).unwrap();
+        let mut noisy = SingleLayer::new(vec![]).unwrap();
+        for i in 0..10 {
+            similar
+                .process_line(format!("user alice logged in from host{}", i))
+                .unwrap();
+            noisy
+                .process_line(format!("job {} {} {} status ok done", i, i * 7, i * 13))
+                .unwrap();
+        }
+        assert_that!(similar.quality()).is_greater_than(noisy.quality());
+        assert_that!(noisy.quality()).is_greater_than(0.5);
+    }
+
+    #[test]
+    fn test_custom_similarity() {
+        #[derive(Debug)]
+        struct Constant(u64);
+        impl Similarity for Constant {
+            fn score(&self, _rec: &Record, _template: &Record) -> Ratio<BigInt> {
+                Ratio::new(BigInt::from(self.0), BigInt::from(1))
+            }
+        }
+        let lines = ["Disk sda1 is 91% full", "Disk fan speed 1200 rpm"];
+        let mut always = SingleLayer::new(vec![]).unwrap();
+        always.set_similarity(Some(Arc::new(Constant(3))));
+        let mut never = SingleLayer::new(vec![]).unwrap();
+        never.set_similarity(Some(Arc::new(Constant(0))));
+        for line in lines.iter().chain(lines.iter()) {
+            always.process_line(line.to_string()).unwrap();
+            never.process_line(line.to_string()).unwrap();
+        }
+        assert_that!(always.group_count()).is_equal_to(1);
+        assert_that!(never.group_count()).is_equal_to(4);
+        assert_that!(always.score_distribution()).is_equal_to(BTreeMap::from([(100, 3)]));
+        always.set_similarity(None);
+        always.process_line(lines[1].to_string()).unwrap();
+        assert_that!(always.group_count()).is_equal_to(2);
+    }
+
+    #[test]
+    fn test_top_groups() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        for (line, repeats) in [
+            ("Fan speed set to 1200 rpm", 2),
+            ("Disk sda1 is 91% full", 5),
+            ("User alice logged in", 1),
+        ] {
+            for _ in 0..repeats {
+                drain.process_line(line.to_string()).unwrap();
+            }
+        }
+        let top = drain.top_groups(2);
+        assert_that!(top.len()).is_equal_to(2);
+        assert_that!(top[0].template()).is_equal_to("Disk sda1 is 91% full".to_string());
+        assert_that!(top[1].count()).is_equal_to(2);
+        assert_that!(drain.top_groups(10).len()).is_equal_to(3);
+    }
+
+    #[test]
+    fn test_strip_punctuation() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.process_line("remote host:".to_string()).unwrap();
+        drain.process_line("remote host".to_string()).unwrap();
+        assert_that!(drain.group_count()).is_equal_to(2);
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.set_strip_punctuation(true);
+        drain.process_line("remote host:".to_string()).unwrap();
+        drain.process_line("remote host".to_string()).unwrap();
+        assert_that!(drain.group_count()).is_equal_to(1);
+    }
+
+    #[test]
+    fn test_coverage() {
+        let training = "Disk sda1 is 91% full\nFan speed set to 1200 rpm\nDisk sdb2 is 97% full\n";
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        for line in training.lines() {
+            drain.process_line(line.to_string()).unwrap();
+        }
+        let seen = drain.coverage(Cursor::new(training)).unwrap();
+        assert_that!(seen.ratio()).is_equal_to(1.0);
+        let novel = "Disk sdc3 is 12% full\nUser alice logged in\n\n";
+        let held_out = drain.coverage(Cursor::new(novel)).unwrap();
+        assert_that!(held_out).is_equal_to(Coverage {
+            matched: 1,
+            unmatched: 1,
+            skipped: 1,
+        });
+        assert_that!(held_out.ratio()).is_equal_to(0.5);
+        assert_that!(drain.group_count()).is_equal_to(2);
+    }
+
+    #[test]
+    fn test_position_weighting() {
+        let lines = [
+            "req alpha beta gamma disk full",
+            "req one two three disk full",
+        ];
+        let mut uniform = SingleLayer::new(vec![]).unwrap();
+        let mut tail = SingleLayer::new(vec![]).unwrap();
+        tail.set_position_weighting(PositionWeighting::Linear);
+        for line in lines {
+            uniform.process_line(line.to_string()).unwrap();
+            tail.process_line(line.to_string()).unwrap();
+        }
+        assert_that!(uniform.group_count()).is_equal_to(2);
+        assert_that!(tail.group_count()).is_equal_to(1);
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        let res = SingleLayer::new(vec!["(unclosed".to_string()]);
+        assert!(matches!(res, Err(DrainError::InvalidRegex(_))));
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        assert!(matches!(
+            drain.set_threshold(1, 0),
+            Err(DrainError::InvalidThreshold {
+                numerator: 1,
+                denominator: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn test_report_interval() {
+        #[derive(Debug, Default)]
+        struct Reports(Mutex<Vec<Vec<Ksuid>>>);
+        impl DrainObserver for Reports {
+            fn on_report(&self, new_groups: &[Ksuid]) {
+                self.0.lock().unwrap().push(new_groups.to_vec());
+            }
+        }
+        let reports = Arc::new(Reports::default());
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.set_observer(reports.clone());
+        drain.set_report_interval(Some(100));
+        let mut created = Vec::new();
+        for i in 0..250 {
+            let line = if i % 60 == 0 {
+                format!("shape{} appeared here", i)
+            } else {
+                "heartbeat ok".to_string()
+            };
+            if let Outcome::NewGroup(id) = drain.process_line(line).unwrap() {
+                created.push(id);
+            }
+        }
+        // groups were created at lines 0, 1, 60, 120, 180 and 240
+        let reports = reports.0.lock().unwrap();
+        assert_that!(reports.len()).is_equal_to(2);
+        assert_that!(reports[0]).is_equal_to(created[..3].to_vec());
+        assert_that!(reports[1]).is_equal_to(created[3..5].to_vec());
+    }
+
+    #[test]
+    fn test_split_bracketed_ids() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.set_split_bracketed_ids(true);
+        for id in [12345, 99999] {
+            drain
+                .process_line(format!("request done (id={})", id))
+                .unwrap();
+        }
+        assert_that!(drain.distinct_templates())
+            .is_equal_to(vec![("request done (id=*)".to_string(), 2)]);
+    }
+
+    #[test]
+    fn test_template_diff() {
+        let mut staging = SingleLayer::new(vec![]).unwrap();
+        let mut prod = SingleLayer::new(vec![]).unwrap();
+        for line in [
+            "Disk sda1 is 91% full",
+            "Disk sdb2 is 91% full",
+            "Cache warmed",
+        ] {
+            staging.process_line(line.to_string()).unwrap();
+        }
+        for line in [
+            "Disk sdc3 is 91% full",
+            "Disk sdd4 is 91% full",
+            "Failover started",
+        ] {
+            prod.process_line(line.to_string()).unwrap();
+        }
+        let diff = staging.template_diff(&prod);
+        let set = |t: &str| BTreeSet::from([t.to_string()]);
+        assert_that!(diff.common).is_equal_to(set("Disk * is 91% full"));
+        assert_that!(diff.only_self).is_equal_to(set("Cache warmed"));
+        assert_that!(diff.only_other).is_equal_to(set("Failover started"));
+    }
+
+    #[test]
+    fn test_score_denominator() {
+        let id_of = |outcome: Outcome| outcome.group_id().unwrap();
+        // 3 of the 6 tokens match, the 3 literal positions all do
+        for (mode, percent) in [
+            (ScoreDenominator::RecordLength, 50),
+            (ScoreDenominator::LiteralPositions, 100),
+        ] {
+            for threshold in [50, 40] {
+                let mut drain = SingleLayer::new(vec![]).unwrap();
+                drain.set_score_denominator(mode);
+                drain.set_threshold(threshold, 100).unwrap();
+                let id = drain.add_template("job * * * finished ok");
+                let outcome = drain
+                    .process_line("job 12 eu 7 finished ok".to_string())
+                    .unwrap();
+                let joins = u64::from(percent) > threshold;
+                assert_that!(id_of(outcome) == id).is_equal_to(joins);
+                let expected = if joins {
+                    BTreeMap::from([(percent, 1)])
+                } else {
+                    BTreeMap::new()
+                };
+                assert_that!(drain.score_distribution()).is_equal_to(expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_warm_interner() {
+        let words = ["WarmProbeAlpha", "WarmProbeBeta", "WarmProbeGamma"];
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.warm_interner(&words);
+        let warmed = words
+            .iter()
+            .map(|w| INTERNER.read().get(w))
+            .collect::<Option<Vec<_>>>()
+            .expect("every word was interned");
+        let outcome = drain.process_line(words.join(" ")).unwrap();
+        let group = drain.iter_groups()[0][0];
+        assert_that!(group.get_id()).is_equal_to(outcome.group_id().unwrap());
+        // the line reused the warmed symbols rather than interning new ones
+        let used = group
+            .event()
+            .into_iter()
+            .map(DefaultSymbol::from)
+            .collect::<Vec<_>>();
+        assert_that!(used).is_equal_to(warmed);
+    }
+
+    #[test]
+    fn test_alignment_fallback() {
+        let lines = ["user alice logged in", "user alice has logged in"];
+        let mut strict = SingleLayer::new(vec![]).unwrap();
+        let mut aligned = SingleLayer::new(vec![]).unwrap();
+        aligned.set_alignment_fallback(Some(1));
+        for line in lines {
+            strict.process_line(line.to_string()).unwrap();
+            aligned.process_line(line.to_string()).unwrap();
+        }
+        assert_that!(strict.group_count()).is_equal_to(2);
+        assert_that!(aligned.group_count()).is_equal_to(1);
+        assert_that!(aligned.distinct_templates())
+            .is_equal_to(vec![("user alice logged in".to_string(), 2)]);
+        // an unbounded difference must not overflow
+        aligned.set_alignment_fallback(Some(usize::MAX));
+        let outcome = aligned
+            .process_line("user alice has now logged in".to_string())
+            .unwrap();
+        assert_that!(outcome.is_new_group()).is_false();
+    }
+
+    #[test]
+    fn test_max_literal_mismatches() {
+        let lines = [
+            "Backup of volume data completed",
+            "Backup of volume logs completed",
+            "Backup of disk logs aborted",
+            "Restore from snapshot logs failed",
+            "Backup of volume cache failed",
+        ];
+        let mut strict = SingleLayer::new(vec![]).unwrap();
+        let mut tolerant = SingleLayer::new(vec![]).unwrap();
+        strict.set_threshold(9, 10).unwrap();
+        tolerant.set_threshold(9, 10).unwrap();
+        tolerant.set_max_literal_mismatches(Some(1));
+        for line in lines {
+            strict.process_line(line.to_string()).unwrap();
+            tolerant.process_line(line.to_string()).unwrap();
+        }
+        assert_that!(strict.group_count()).is_equal_to(5);
+        assert_that!(tolerant.group_count()).is_equal_to(3);
+        assert_that!(tolerant.distinct_templates())
+            .contains(("Backup of volume * *".to_string(), 3));
+        // the wildcard doesn't count towards the score of the last line
+        assert_that!(tolerant.score_distribution()).is_equal_to(BTreeMap::from([(60, 1), (80, 1)]));
+    }
+
+    #[test]
+    fn test_find_groups() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        for line in [
+            "Login failed for alice",
+            "Backup completed in 42s",
+            "Upload of report.pdf failed",
+        ] {
+            drain.process_line(line.to_string()).unwrap();
+        }
+        let found = drain.find_groups("failed").unwrap();
+        assert_that!(found.len()).is_equal_to(2);
+        assert!(found.iter().all(|g| g.template().contains("failed")));
+        assert!(matches!(
+            drain.find_groups("(unclosed"),
+            Err(DrainError::InvalidRegex(_))
+        ));
+    }
+
+    #[test]
+    fn test_rebucket_after_masking() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        let id = drain
+            .process_line("10.0.0.1 connected to db".to_string())
+            .unwrap()
+            .group_id()
+            .unwrap();
+        drain
+            .domain
+            .push(Regex::new(DomainPreset::IPv4.pattern()).unwrap());
+        let line = "10.0.0.9 connected to db".to_string();
+        assert_that!(drain.classify_line(line.clone()).unwrap()).is_equal_to(Outcome::Unmatched);
+        drain.rebucket();
+        assert_that!(drain.classify_line(line.clone()).unwrap()).is_equal_to(Outcome::Matched(id));
+        assert_that!(drain.process_line(line).unwrap()).is_equal_to(Outcome::Matched(id));
+        assert_that!(drain.group_count()).is_equal_to(1);
+    }
+
+    #[test]
+    fn test_rebucket_keeps_oldest_first() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        let ids = ["10.0.0.1 connected to db", "10.0.0.2 connected to db"]
+            .map(|line| drain.process_line(line.to_string()).unwrap());
+        drain
+            .domain
+            .push(Regex::new(DomainPreset::IPv4.pattern()).unwrap());
+        // both groups now share a bucket and score the same, the older one wins the tie
+        for _ in 0..8 {
+            drain.rebucket();
+            let outcome = drain
+                .classify_line("10.0.0.9 connected to db".to_string())
+                .unwrap();
+            assert_that!(outcome.group_id()).is_equal_to(ids[0].group_id());
+        }
+    }
+
+    #[test]
+    fn test_example_policy() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        for i in 0..3 {
+            drain.process_line(format!("Disk sd{} is full", i)).unwrap();
+        }
+        drain.set_example_policy(ExamplePolicy::LastN(1));
+        drain.process_line("Disk sd9 is full".to_string()).unwrap();
+        let group = drain.iter_groups()[0][0];
+        assert_that!(group.count()).is_equal_to(4);
+        let kept = group
+            .examples_iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_that!(kept).is_equal_to(vec!["Disk sd9 is full".to_string()]);
+    }
+
+    #[test]
+    fn test_with_presets() {
+        let lines = [
+            "Connection from 10.0.0.1 closed",
+            "Connection from 192.168.1.20 closed",
+        ];
+        let mut plain = SingleLayer::new(vec![]).unwrap();
+        let mut masked = SingleLayer::with_presets(&[DomainPreset::IPv4, DomainPreset::Email]);
+        for drain in [&mut plain, &mut masked] {
+            drain.set_threshold(99, 100).unwrap();
+            for line in lines {
+                drain.process_line(line.to_string()).unwrap();
+            }
+        }
+        assert_that!(plain.group_count()).is_equal_to(2);
+        assert_that!(masked.distinct_templates())
+            .is_equal_to(vec![("Connection from * closed".to_string(), 2)]);
+    }
+
+    #[test]
+    fn test_domain_masking() {
+        let mut drain = SingleLayer::new(vec![r"\bid-[0-9]+\b".to_string()]).unwrap();
+        drain.set_threshold(99, 100).unwrap();
+        for line in ["Job id-17 finished", "Job id-42 finished"] {
+            drain.process_line(line.to_string()).unwrap();
+        }
+        assert_that!(drain.distinct_templates())
+            .is_equal_to(vec![("Job * finished".to_string(), 2)]);
+    }
+
+    #[test]
+    fn test_case_insensitive_clustering_keeps_first_case() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.set_case_fold(true);
+        let first = drain.process_line("ERROR foo".to_string()).unwrap();
+        let second = drain.process_line("error foo".to_string()).unwrap();
+        drain.process_line("Error Foo".to_string()).unwrap();
+        assert_that!(second).is_equal_to(Outcome::Matched(first.group_id().unwrap()));
+        assert_that!(drain.group_count()).is_equal_to(1);
+        assert_that!(drain.distinct_templates()).is_equal_to(vec![("ERROR foo".to_string(), 3)]);
+        drain
+            .process_line("User ALICE logged IN".to_string())
+            .unwrap();
+        drain
+            .process_line("user bob Logged in".to_string())
+            .unwrap();
+        assert_that!(drain.group_count()).is_equal_to(2);
+        let group = drain.find_groups("logged").unwrap()[0];
+        assert_that!(group.template()).is_equal_to("User * logged IN".to_string());
+        let re = group.to_regex().unwrap();
+        assert_that!(re.is_match("USER carol LOGGED in")).is_true();
+    }
+
+    #[test]
+    fn test_dump_interner() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain
+            .process_line("Dumped quokka interner words".to_string())
+            .unwrap();
+        let dump = drain.dump_interner();
+        for word in ["Dumped", "quokka", "interner", "words"] {
+            assert_that!(dump.iter().any(|(_, w)| w == word)).is_true();
+        }
+        for (sym, word) in dump.iter().take(50) {
+            assert_that!(drain.resolve(*sym)).is_equal_to(word);
+            assert_that!(*drain.resolve_ref(*sym) == **word).is_true();
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_parallel_scoring_matches_serial() {
+        let mut serial = SingleLayer::new(vec![]).unwrap();
+        serial.set_threshold(7, 10).unwrap();
+        for i in 0..64 {
+            serial
+                .process_line(format!("Event p q a{} b{}", i, i))
+                .unwrap();
+        }
+        assert_that!(serial.group_count()).is_equal_to(64);
+        let mut parallel = serial.clone();
+        parallel.set_parallel_scoring(Some(8));
+        // every line ties between two groups, the older one has to win either way
+        for i in 0..63 {
+            let line = format!("Event p q a{} b{}", i, i + 1);
+            let expected = serial.classify_line(line.clone()).unwrap();
+            assert_that!(expected.group_id()).is_some();
+            assert_that!(parallel.classify_line(line).unwrap()).is_equal_to(expected);
+        }
+        let json = serde_json::to_string(&parallel).unwrap();
+        let restored: SingleLayer = serde_json::from_str(&json).unwrap();
+        assert_that!(restored.parallel_scoring).is_equal_to(Some(8));
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain
+            .process_line("Disk sda1 is 91% full".to_string())
+            .unwrap();
+        drain
+            .process_line("User alice logged in".to_string())
+            .unwrap();
+        let before = drain.snapshot();
+        assert_that!(before.diff(&before).is_empty()).is_true();
+        drain
+            .process_line("Disk sdb2 is 91% full".to_string())
+            .unwrap();
+        let added = drain
+            .process_line("Connection reset by peer".to_string())
+            .unwrap()
+            .group_id()
+            .unwrap();
+        let report = before.diff(&drain.snapshot());
+        assert_that!(report.added.keys().collect::<Vec<_>>()).is_equal_to(vec![&added.serialize()]);
+        assert_that!(report.removed.is_empty()).is_true();
+        assert_that!(report.modified.len()).is_equal_to(1);
+        let (old, new) = report.modified.values().next().unwrap();
+        assert_that!(old.as_str()).is_equal_to("Disk sda1 is 91% full");
+        assert_that!(new.as_str()).is_equal_to("Disk * is 91% full");
+    }
+
+    #[test]
+    fn test_first_token_normalizer() {
+        let lines = [
+            "2023-01-01T10:00:00 worker started job",
+            "2024-05-06T11:12:13 worker started job",
+        ];
+        let mut plain = SingleLayer::new(vec![]).unwrap();
+        let mut normalized = SingleLayer::new(vec![]).unwrap();
+        normalized.set_first_token_normalizer(Some(Arc::new(|word: &str| {
+            word.trim_start_matches(|c: char| c.is_ascii_digit() || "-:T".contains(c))
+                .to_string()
+        })));
+        for line in lines {
+            plain.process_line(line.to_string()).unwrap();
+            normalized.process_line(line.to_string()).unwrap();
+        }
+        assert_that!(plain.group_count()).is_equal_to(2);
+        assert_that!(normalized.group_count()).is_equal_to(1);
+        assert_that!(normalized.iter_groups()[0][0].count()).is_equal_to(2);
+    }
+
+    #[test]
+    fn test_process_reader_ndjson() {
+        let input = "Disk sda1 is 91% full\nDisk sdb2 is 91% full\n\nQuote \"this\"\ttab\n";
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        let mut out = Vec::new();
+        drain
+            .process_reader_ndjson(Cursor::new(input), &mut out)
+            .unwrap();
+        let objects = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_that!(objects.len()).is_equal_to(4);
+        assert_that!(objects[0]["new_group"].as_bool()).is_equal_to(Some(true));
+        assert_that!(objects[1]["outcome"].as_str()).is_equal_to(Some("matched"));
+        assert_that!(objects[1]["score"].as_f64()).is_equal_to(Some(0.8));
+        assert_that!(objects[1]["group_id"]).is_equal_to(&objects[0]["group_id"]);
+        assert_that!(objects[2]["group_id"].is_null()).is_true();
+        assert_that!(objects[3]["line"].as_str()).is_equal_to(Some("Quote \"this\"\ttab"));
+    }
+
+    #[test]
+    fn test_max_groups_catch_all() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.set_max_groups(Some(3));
+        let unique = [
+            "Disk sda1 is 91% full",
+            "User alice logged in",
+            "Cache warmed",
+            "Backup finished without errors today",
+            "Kernel panic",
+            "Fan speed critical on rack seven now",
+        ];
+        let outcomes = unique
+            .iter()
+            .map(|line| drain.process_line(line.to_string()).unwrap())
+            .collect::<Vec<Outcome>>();
+        assert_that!(drain.group_count()).is_equal_to(3);
+        let catch_all = drain.catch_all().unwrap();
+        assert_that!(catch_all.template()).is_equal_to("*".to_string());
+        assert_that!(catch_all.count()).is_equal_to(3);
+        for outcome in &outcomes[3..] {
+            assert_that!(*outcome).is_equal_to(Outcome::Overflow(catch_all.get_id()));
+        }
+        // Lines matching existing groups still join them
+        let outcome = drain
+            .process_line("Disk sdb2 is 45% full".to_string())
+            .unwrap();
+        assert_that!(outcome.group_id()).is_equal_to(outcomes[0].group_id());
+        let json = serde_json::to_string(&drain).unwrap();
+        let mut restored: SingleLayer = serde_json::from_str(&json).unwrap();
+        assert_that!(restored.catch_all().unwrap().count()).is_equal_to(3);
+        let outcome = restored
+            .process_line("Power supply two lost input".to_string())
+            .unwrap();
+        assert_that!(outcome).is_equal_to(outcomes[3]);
+    }
+
+    #[test]
+    fn test_deserialize_state_without_settings() {
+        let json = r#"{"domain":[],"threshold":["1","2"],"groups":[]}"#;
+        let restored: SingleLayer = serde_json::from_str(json).unwrap();
+        assert_that!(restored.coalesce_numeric).is_false();
+        assert_that!(restored.group_count()).is_equal_to(0);
+    }
+
+    #[test]
+    fn test_serde_keeps_configuration() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.set_min_tokens(3);
+        drain.freeze();
+        drain.set_stable_after(Some(5));
+        drain.set_idf_scoring(true);
+        drain.set_unify_endpoints(true);
+        drain.set_max_tokens(Some(40), LongLinePolicy::Truncate);
+        drain.set_position_weighting(PositionWeighting::Linear);
+        drain.set_score_denominator(ScoreDenominator::LiteralPositions);
+        drain.set_alignment_fallback(Some(2));
+        drain.set_max_literal_mismatches(Some(1));
+        drain.set_match_cache(Some(16));
+        drain.set_report_interval(Some(100));
+        drain.process_line("Disk sda1 is full".to_string()).unwrap();
+        let json = serde_json::to_string(&drain).unwrap();
+        let restored: SingleLayer = serde_json::from_str(&json).unwrap();
+        assert_that!(restored.min_tokens).is_equal_to(3);
+        assert_that!(restored.is_frozen()).is_true();
+        assert_that!(restored.stable_after).is_equal_to(Some(5));
+        assert_that!(restored.unify_endpoints).is_true();
+        assert_that!(restored.max_tokens).is_equal_to(Some((40, LongLinePolicy::Truncate)));
+        assert_that!(restored.position_weighting).is_equal_to(PositionWeighting::Linear);
+        assert_that!(restored.score_denominator).is_equal_to(ScoreDenominator::LiteralPositions);
+        assert_that!(restored.alignment_fallback).is_equal_to(Some(2));
+        assert_that!(restored.max_literal_mismatches).is_equal_to(Some(1));
+        assert_that!(restored.match_cache.as_ref().map(|c| c.capacity())).is_equal_to(Some(16));
+        assert_that!(restored.report_interval).is_equal_to(Some(100));
+        let unseen = Record::new("zebra".to_string()).first().unwrap();
+        let weight = |d: &SingleLayer| d.idf.as_ref().map(|idf| idf.weight(unseen));
+        assert_that!(weight(&restored)).is_equal_to(weight(&drain));
+    }
+
+    #[test]
+    fn test_debug_dump() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        for line in [
+            "Disk sda1 is 91% full",
+            "Disk sdb2 is 45% full",
+            "User \"alice\" logged in",
+            "Cache warmed",
+        ] {
+            drain.process_line(line.to_string()).unwrap();
+        }
+        let dump = serde_json::from_str::<serde_json::Value>(&drain.debug_dump()).unwrap();
+        let groups = dump["buckets"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|b| b["groups"].as_array().unwrap().clone())
+            .collect::<Vec<_>>();
+        assert_that!(groups.len()).is_equal_to(drain.group_count());
+        for group in drain.iter_groups().into_iter().flatten() {
+            let dumped = groups
+                .iter()
+                .find(|g| g["id"].as_str() == Some(group.get_id().serialize().as_str()))
+                .unwrap();
+            assert_that!(dumped["template"].as_str()).is_equal_to(Some(group.template().as_str()));
+            assert_that!(dumped["examples"].as_u64()).is_equal_to(Some(group.len() as u64));
+        }
+        assert_that!(dump["buckets"][0]["length"].as_u64()).is_equal_to(Some(2));
+    }
+
+    #[test]
+    fn test_classify_line() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        let id = drain
+            .process_line("Disk sda1 is 91% full".to_string())
+            .unwrap()
+            .group_id()
+            .unwrap();
+        let res = drain.classify_line("Disk sdb2 is 91% full".to_string());
+        assert_that!(res.unwrap()).is_equal_to(Outcome::Matched(id));
+        let res = drain.classify_line("Fan speed set to 1200 rpm".to_string());
+        assert_that!(res.unwrap()).is_equal_to(Outcome::Unmatched);
+        // classifying never adds examples or groups
+        assert_that!(drain.group_count()).is_equal_to(1);
+        assert_that!(drain.iter_groups()[0][0].count()).is_equal_to(1);
+    }
+
+    fn ingest(drain: &mut dyn Drain, lines: &[&str]) -> usize {
+        drain.set_threshold(1, 2).unwrap();
+        for line in lines {
+            drain.process_line(line.to_string()).unwrap();
+        }
+        drain.iter_groups().into_iter().flatten().count()
+    }
+
+    #[test]
+    fn test_drain_trait_object() {
+        let lines = [
+            "Disk sda1 is 91% full",
+            "Disk sdb2 is 93% full",
+            "Fan failure",
+        ];
+        let mut drains: Vec<Box<dyn Drain>> = vec![Box::new(SingleLayer::new(vec![]).unwrap())];
+        for drain in &mut drains {
+            assert_that!(ingest(drain.as_mut(), &lines)).is_equal_to(2);
+            let res = drain.classify_line("Fan failure".to_string()).unwrap();
+            assert_that!(res.group_id()).is_some();
+        }
+    }
+
+    #[test]
+    fn test_split_key_value() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.set_split_key_value(true);
+        drain
+            .process_line("request done status=200 bytes=512".to_string())
+            .unwrap();
+        drain
+            .process_line("request done status=404 bytes=512".to_string())
+            .unwrap();
+        assert_that!(drain.distinct_templates())
+            .is_equal_to(vec![("request done status=* bytes=512".to_string(), 2)]);
+    }
+
+    #[test]
+    fn test_exceeds_threshold_matches_ratio() {
+        let thresholds = [
+            Ratio::new(BigInt::from(1), BigInt::from(2)),
+            Ratio::new(BigInt::from(2), BigInt::from(3)),
+            Ratio::new(BigInt::from(-1), BigInt::from(3)),
+            Ratio::new(BigInt::from(u64::MAX) * 4, BigInt::from(u64::MAX) * 5),
+        ];
+        for threshold in &thresholds {
+            for length in 1..12_u64 {
+                for score in 0..=length {
+                    let expected =
+                        Ratio::new(BigInt::from(score), BigInt::from(length)) > *threshold;
+                    assert_that!(SingleLayer::exceeds_threshold(threshold, score, length))
+                        .is_equal_to(expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_length_histogram() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        for line in [
+            "alpha beta gamma",
+            "delta epsilon zeta",
+            "one two three four five",
+        ] {
+            drain.process_line(line.to_string()).unwrap();
+        }
+        let histogram = drain.length_histogram();
+        assert_that!(histogram.into_iter().collect::<Vec<_>>()).is_equal_to(vec![(3, 2), (5, 1)]);
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_simulate_threshold() {
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        let lines = ["foo.bar.com", "bork.bork.com", "quux.example.org"]
+            .iter()
+            .map(|host| format!("Message send failed to remote host: {}", host))
+            .collect::<Vec<String>>();
+        let loose = drain.simulate_threshold(&lines, Ratio::new(BigInt::from(1), BigInt::from(2)));
+        let strict =
+            drain.simulate_threshold(&lines, Ratio::new(BigInt::from(9), BigInt::from(10)));
+        assert_that(&loose).is_equal_to(1);
+        assert_that(&strict).is_equal_to(3);
+        assert_that(&drain.group_count()).is_equal_to(0);
+        drain.process_line(lines[0].clone()).unwrap();
+        drain.simulate_threshold(&lines, Ratio::new(BigInt::from(9), BigInt::from(10)));
+        assert_that(&drain.group_count()).is_equal_to(1);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_process_reader_with_checkpoint() {
+        let input = "Disk sda1 is 91% full\nDisk sdb2 is 45% full\nUser alice logged in\n\
+                     User bob logged in\nCache warmed in 12 ms\n";
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        let mut out = Vec::new();
+        drain
+            .process_reader_with_checkpoint(Cursor::new(input), 2, &mut out)
+            .unwrap();
+        let restored = SingleLayer::last_checkpoint(Cursor::new(&out))
+            .unwrap()
+            .unwrap();
+        assert_that!(restored.distinct_templates()).is_equal_to(drain.distinct_templates());
+        // Cut the stream part way through the second checkpoint, as a crash would
+        let first_len = u64::from_le_bytes(out[..8].try_into().unwrap()) as usize;
+        let crashed = &out[..8 + first_len + 12];
+        let mut restored = SingleLayer::last_checkpoint(Cursor::new(crashed))
+            .unwrap()
+            .unwrap();
+        assert_that!(restored.distinct_templates())
+            .is_equal_to(vec![("Disk * is * full".to_string(), 2)]);
+        let outcome = restored
+            .process_line("Disk sdc3 is 10% full".to_string())
+            .unwrap();
+        assert_that!(outcome.is_new_group()).is_false();
+        assert_that!(SingleLayer::last_checkpoint(Cursor::new(&[] as &[u8])).unwrap()).is_none();
+        // A garbage length after a complete checkpoint is ignored without allocating it
+        let mut garbage = out[..8 + first_len].to_vec();
+        garbage.extend_from_slice(&u64::MAX.to_le_bytes());
+        garbage.extend_from_slice(b"torn");
+        let restored = SingleLayer::last_checkpoint(Cursor::new(garbage)).unwrap();
+        assert_that!(restored.is_some()).is_true();
+    }
+
+    #[cfg(feature = "bincode")]
+    #[traced_test]
+    #[test]
+    fn test_bincode_round_trip() {
+        let mut drain = SingleLayer::new(vec![r"\d+".to_string()]).unwrap();
+        for line in [
+            "Message send failed to remote host: foo.bar.com",
+            "Message send failed to remote host: bork.bork.com",
+            "Unknown error received from peer",
+        ] {
+            drain.process_line(line.to_string()).unwrap();
+        }
+        let bytes = drain.to_bytes().unwrap();
+        let json = serde_json::to_vec(&drain).unwrap();
+        assert_that(&(bytes.len() * 2)).is_less_than(json.len());
+
+        let restored = SingleLayer::from_bytes(&bytes).unwrap();
+        assert_that(&restored.group_count()).is_equal_to(drain.group_count());
+        assert_that(&restored.threshold).is_equal_to(drain.threshold.clone());
+        assert_that(&restored.domain[0].as_str()).is_equal_to(r"\d+");
+        let templates = |d: &SingleLayer| {
+            let mut t = d
+                .iter_groups()
+                .into_iter()
+                .flatten()
+                .map(|g| g.template())
+                .collect::<Vec<String>>();
+            t.sort();
+            t
+        };
+        assert_that(&templates(&restored)).is_equal_to(templates(&drain));
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_observer_counts() {
+        let observer = Arc::new(CountingObserver::default());
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.set_observer(observer.clone());
+        for line in [
+            "Message send failed to remote host: foo.bar.com",
+            "Message send failed to remote host: bork.bork.com",
+            "Message send failed to remote host: quux.bork.com",
+            "Unknown error received from peer",
+        ] {
+            drain.process_line(line.to_string()).unwrap();
+        }
+        assert_that(&observer.new_groups.load(Ordering::SeqCst)).is_equal_to(2);
+        assert_that(&observer.matches.load(Ordering::SeqCst)).is_equal_to(2);
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_observer_stable() {
+        let observer = Arc::new(CountingObserver::default());
+        let mut drain = SingleLayer::new(vec![]).unwrap();
+        drain.set_observer(observer.clone());
+        drain.set_stable_after(Some(2));
+        for host in ["foo", "bar", "bar", "bar", "bar"] {
+            drain
+                .process_line(format!("Message send failed to remote host: {}", host))
+                .unwrap();
+        }
+        // the first match introduces a variable, the next two make it stable, the last is past it
+        assert_that(&observer.stable.load(Ordering::SeqCst)).is_equal_to(1);
     }
 }
@@ -0,0 +1,96 @@
+// Copyright Nicholas Harring. All rights reserved.
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the Server Side Public License, version 1, as published by MongoDB, Inc.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the Server Side Public License for more details. You should have received a copy of the
+// Server Side Public License along with this program.
+// If not, see <http://www.mongodb.com/licensing/server-side-public-license>.
+
+use std::{error::Error, fmt, io};
+
+/// Errors returned by the public API of this crate
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DrainError {
+    /// A domain pattern passed to a drain failed to compile
+    InvalidRegex(regex::Error),
+    /// A similarity threshold which can't be used, such as one with a zero denominator
+    InvalidThreshold { numerator: u64, denominator: u64 },
+    /// Two groups whose event lengths are too far apart to be merged
+    LengthMismatch { left: usize, right: usize },
+    /// Reading input failed
+    Io(io::Error),
+    /// Encoding or decoding a drain failed
+    Serde(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for DrainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DrainError::InvalidRegex(e) => write!(f, "invalid domain pattern: {}", e),
+            DrainError::InvalidThreshold {
+                numerator,
+                denominator,
+            } => write!(f, "invalid threshold {}/{}", numerator, denominator),
+            DrainError::LengthMismatch { left, right } => {
+                write!(f, "can't merge groups of {} and {} tokens", left, right)
+            },
+            DrainError::Io(e) => write!(f, "i/o error: {}", e),
+            DrainError::Serde(e) => write!(f, "serialization error: {}", e),
+        }
+    }
+}
+
+impl Error for DrainError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DrainError::InvalidRegex(e) => Some(e),
+            DrainError::InvalidThreshold { .. } | DrainError::LengthMismatch { .. } => None,
+            DrainError::Io(e) => Some(e),
+            DrainError::Serde(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+impl From<regex::Error> for DrainError {
+    fn from(e: regex::Error) -> Self {
+        DrainError::InvalidRegex(e)
+    }
+}
+
+impl From<io::Error> for DrainError {
+    fn from(e: io::Error) -> Self {
+        DrainError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for DrainError {
+    fn from(e: serde_json::Error) -> Self {
+        DrainError::Serde(Box::new(e))
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl From<bincode::Error> for DrainError {
+    fn from(e: bincode::Error) -> Self {
+        DrainError::Serde(e)
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use std::error::Error;
+
+    use spectral::prelude::*;
+
+    use super::DrainError;
+
+    #[test]
+    fn test_io_errors_keep_their_source() {
+        let err = DrainError::from(std::io::Error::other("disk gone"));
+        assert_that!(err.to_string()).is_equal_to("i/o error: disk gone".to_string());
+        assert_that!(err.source().is_some()).is_true();
+    }
+}
@@ -14,5 +14,10 @@ extern crate custom_derive;
 extern crate enum_derive;
 
 pub mod drains;
+pub mod error;
 pub mod log_group;
 pub mod record;
+mod serialize;
+
+pub use error::DrainError;
+pub use log_group::common_template;
@@ -8,25 +8,107 @@
 // Server Side Public License along with this program.
 // If not, see <http://www.mongodb.com/licensing/server-side-public-license>.
 
-use std::{borrow::Borrow, collections::HashMap, fmt};
+mod quantiles;
+
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use anyhow::Error;
 use chrono::{DateTime, Utc};
+use regex::Regex;
 use rksuid::Ksuid;
+use serde::{Deserialize, Deserializer, Serialize};
 use tracing::{debug, instrument};
 
-use crate::record::{tokens::Token, Record};
+use self::quantiles::QuantileSketch;
+use crate::{
+    error::DrainError,
+    record::{
+        tokens::{GrokSet, Grokker, Token},
+        Record,
+    },
+};
+
+// Creation order of groups, ids only order them to the second
+static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(1);
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LogGroup {
+    #[serde(with = "crate::serialize::ksuid")]
     pub id: Ksuid,
     event: Record,
-    examples: Vec<Record>,
+    #[serde(default)]
+    origin: Option<Record>,
+    examples: VecDeque<Record>,
     pub variables: HashMap<usize, Token>,
+    coalesce_numeric: bool,
+    numeric: HashSet<usize>,
+    // Only `None` for groups saved before it was recorded, see [LogGroup::last_seen]
+    #[serde(default)]
+    last_seen: Option<DateTime<Utc>>,
+    #[serde(default)]
+    stable_count: usize,
+    // Groups saved before this was counted held at least the line which created them
+    #[serde(default = "one")]
+    count: u64,
+    // Every distinct template this group has had, oldest first
+    #[serde(default)]
+    template_history: Vec<(DateTime<Utc>, String)>,
+    // Whether the last token may be absent, after merging with a group one token shorter or longer
+    #[serde(default)]
+    optional_tail: bool,
+    #[serde(default)]
+    example_policy: ExamplePolicy,
+    // Examples offered to the group, including those the policy didn't keep
+    #[serde(default)]
+    examples_seen: u64,
+    // Fixed per group so reservoir sampling is reproducible
+    #[serde(default)]
+    sample_seed: u64,
+    // Distribution of the values at each variable position which has only held numbers
+    #[serde(default)]
+    value_sketches: HashMap<usize, QuantileSketch>,
+    // Variable positions which have held something other than a number
+    #[serde(default)]
+    non_numeric: HashSet<usize>,
+    // Labels attached by users, such as marking a group as known noise
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    // Position in NEXT_SEQUENCE, zero for groups saved before it was recorded
+    #[serde(default, deserialize_with = "restore_sequence")]
+    sequence: u64,
+}
+
+fn one() -> u64 {
+    1
+}
+
+/// Load a group's sequence, moving [NEXT_SEQUENCE] past it so groups created afterwards sort later
+fn restore_sequence<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let sequence = u64::deserialize(deserializer)?;
+    NEXT_SEQUENCE.fetch_max(sequence + 1, Ordering::Relaxed);
+    Ok(sequence)
+}
+
+/// Which examples a [LogGroup] keeps once more have been added than it should store
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExamplePolicy {
+    /// Keep every example
+    #[default]
+    All,
+    /// Keep the first `n` examples and drop the rest
+    FirstN(usize),
+    /// Keep the most recent `n` examples, dropping the oldest as new ones arrive
+    LastN(usize),
+    /// Keep a uniform random sample of `n` examples from everything added
+    Reservoir(usize),
 }
 
 /// A wildcard is an offset and a typed token
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Wildcard((usize, Token));
 
 impl fmt::Display for Wildcard {
@@ -38,19 +120,114 @@ impl fmt::Display for Wildcard {
 impl LogGroup {
     #[instrument(level = "trace", skip(event))]
     pub fn new(event: Record) -> Self {
-        Self {
+        let mut group = Self {
             id: event.uid,
+            origin: Some(event.clone()),
+            sample_seed: event.normalized_hash(),
             event,
-            examples: vec![],
+            examples: VecDeque::new(),
             variables: HashMap::new(),
+            coalesce_numeric: false,
+            numeric: HashSet::new(),
+            last_seen: Some(Utc::now()),
+            stable_count: 0,
+            count: 1,
+            template_history: vec![],
+            optional_tail: false,
+            example_policy: ExamplePolicy::All,
+            examples_seen: 0,
+            value_sketches: HashMap::new(),
+            non_numeric: HashSet::new(),
+            metadata: HashMap::new(),
+            sequence: NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed),
+        };
+        group.record_template();
+        group
+    }
+
+    /// Build a group from a curated template rather than an observed line. Tokens written as `*`
+    /// or `<*>` become variables. The group has no origin and starts with a count of zero.
+    #[instrument(level = "trace", skip(template))]
+    pub fn from_template(mut template: Record) -> Self {
+        let mut variables = HashMap::new();
+        for (idx, (_, token)) in template.inner.inner.iter_mut().enumerate() {
+            if matches!(token.to_string().as_str(), "*" | "<*>") {
+                *token = Token::Wildcard;
+                variables.insert(idx, Token::Wildcard);
+            }
+        }
+        let mut group = Self::new(template);
+        group.origin = None;
+        group.variables = variables;
+        group.count = 0;
+        group.template_history.clear();
+        group.record_template();
+        group
+    }
+
+    /// When enabled, adjacent variable positions which hold numeric values in both the base
+    /// event and the example are treated as a single variable span
+    #[instrument(level = "trace", skip(self))]
+    pub fn set_coalesce_numeric(&mut self, coalesce: bool) {
+        self.coalesce_numeric = coalesce;
+    }
+
+    /// Choose which examples to keep, dropping any stored examples the policy doesn't allow for.
+    /// Examples which aren't kept still count towards [LogGroup::count] and still reveal
+    /// variables.
+    #[instrument(level = "trace", skip(self))]
+    pub fn set_example_policy(&mut self, policy: ExamplePolicy) {
+        self.example_policy = policy;
+        match policy {
+            ExamplePolicy::All => {},
+            ExamplePolicy::FirstN(n) | ExamplePolicy::Reservoir(n) => self.examples.truncate(n),
+            ExamplePolicy::LastN(n) => {
+                let excess = self.examples.len().saturating_sub(n);
+                self.examples.drain(..excess);
+            },
         }
     }
 
     #[instrument(level = "trace", skip(self, rec))]
     pub fn add_example(&mut self, rec: Record) {
         let vars = self.discover_variables(&rec).unwrap();
-        self.examples.push(rec);
-        if !vars.is_empty() {
+        if self.coalesce_numeric {
+            self.mark_numeric(&vars, &rec);
+        }
+        self.absorb(rec, vars);
+    }
+
+    /// Add an example which may have a different number of tokens than the base event. The two
+    /// are aligned on their longest common subsequence of tokens and every base event position
+    /// outside it becomes a variable. Tokens only the example has can't be represented in the
+    /// template and are ignored.
+    #[instrument(level = "trace", skip(self, rec))]
+    pub fn add_aligned_example(&mut self, rec: Record) {
+        let aligned = self
+            .event
+            .lcs_alignment(&rec)
+            .into_iter()
+            .map(|(idx, _)| idx)
+            .collect::<HashSet<usize>>();
+        let vars = (0..self.event.len())
+            .filter(|idx| !aligned.contains(idx) && !self.variables.contains_key(idx))
+            .map(|idx| Wildcard((idx, Token::Wildcard)))
+            .collect::<Vec<_>>();
+        self.absorb(rec, vars);
+    }
+
+    /// Store `rec` as an example and apply the variables it revealed
+    fn absorb(&mut self, rec: Record, vars: Vec<Wildcard>) {
+        if rec.len() == self.event.len() {
+            self.track_values(&rec, &vars);
+        }
+        self.keep_example(rec);
+        self.count += 1;
+        self.last_seen = Some(Utc::now());
+        if vars.is_empty() {
+            self.stable_count += 1;
+        } else {
+            self.stable_count = 0;
             self.update_variables(vars);
         }
     }
@@ -60,9 +237,44 @@ impl LogGroup {
         &self.event
     }
 
+    /// Store `rec` if the example policy allows for it
+    fn keep_example(&mut self, rec: Record) {
+        self.examples_seen += 1;
+        match self.example_policy {
+            ExamplePolicy::All => self.examples.push_back(rec),
+            ExamplePolicy::FirstN(n) => {
+                if self.examples.len() < n {
+                    self.examples.push_back(rec);
+                }
+            },
+            ExamplePolicy::LastN(0) => {},
+            ExamplePolicy::LastN(n) => {
+                if self.examples.len() >= n {
+                    self.examples.pop_front();
+                }
+                self.examples.push_back(rec);
+            },
+            ExamplePolicy::Reservoir(n) => {
+                if self.examples.len() < n {
+                    self.examples.push_back(rec);
+                } else {
+                    // Algorithm R, the new example replaces a kept one with probability n / seen
+                    let slot =
+                        splitmix64(self.sample_seed ^ self.examples_seen) % self.examples_seen;
+                    if let Some(kept) = usize::try_from(slot)
+                        .ok()
+                        .and_then(|slot| self.examples.get_mut(slot))
+                    {
+                        *kept = rec;
+                    }
+                }
+            },
+        }
+    }
+
     /// Compare a record with this log group and identify positions which qualify as variables, returned as vector of [Wildcard]
     #[instrument(level = "trace", skip(self, rec))]
-    pub fn discover_variables(&self, rec: &Record) -> Result<Vec<Wildcard>, Error> {
+    pub fn discover_variables(&self, rec: &Record) -> Result<Vec<Wildcard>, DrainError> {
         let f = self
             .event
             .borrow()
@@ -94,6 +306,380 @@ impl LogGroup {
             let (offset, _) = self.event.inner.inner[var.0 .0].clone();
             self.event.inner.inner[var.0 .0] = (offset, var.0 .1);
         }
+        self.record_template();
+    }
+
+    /// Fold `other` into this group. Positions where the two templates differ become variables and
+    /// `other`'s examples and count are added to this group's. Groups whose lengths differ by one
+    /// are merged on their shared leading positions, with the extra last token becoming a variable
+    /// which may be absent, rendered as `*?` in the template. Lengths further apart can't be
+    /// merged.
+    #[instrument(level = "trace", skip_all)]
+    pub fn merge(&mut self, other: LogGroup) -> Result<(), DrainError> {
+        let (ours, theirs) = (self.event.len(), other.event.len());
+        if ours.abs_diff(theirs) > 1 {
+            return Err(DrainError::LengthMismatch {
+                left: ours,
+                right: theirs,
+            });
+        }
+        let mut vars = self
+            .event
+            .borrow()
+            .into_iter()
+            .zip(other.event.borrow().into_iter())
+            .enumerate()
+            .filter(|(idx, (ours, theirs))| !self.variables.contains_key(idx) && ours != theirs)
+            .map(|(idx, _)| Wildcard((idx, Token::Wildcard)))
+            .collect::<Vec<_>>();
+        if theirs > ours {
+            self.event.inner.push_wildcard();
+            vars.push(Wildcard((ours, Token::Wildcard)));
+        } else if ours > theirs && !self.variables.contains_key(&theirs) {
+            vars.push(Wildcard((theirs, Token::Wildcard)));
+        }
+        self.optional_tail |= ours != theirs || other.optional_tail;
+        self.last_seen = Some(self.last_seen().max(other.last_seen()));
+        for rec in other.origin.into_iter().chain(other.examples) {
+            self.keep_example(rec);
+        }
+        self.count += other.count;
+        for (key, value) in other.metadata {
+            self.metadata.entry(key).or_insert(value);
+        }
+        for (idx, sketch) in &other.value_sketches {
+            if let Some(ours) = self.value_sketches.get_mut(idx) {
+                ours.merge(sketch);
+            }
+        }
+        // a position which held something other than a number in either group isn't tracked
+        for idx in other.non_numeric {
+            self.value_sketches.remove(&idx);
+            self.non_numeric.insert(idx);
+        }
+        if !vars.is_empty() {
+            self.stable_count = 0;
+            self.update_variables(vars);
+        }
+        self.record_template();
+        Ok(())
+    }
+
+    /// Attach a label to the group for downstream tooling, returning the value it replaced. Labels
+    /// are kept when the group is serialized and when another group is merged into it.
+    #[instrument(level = "trace", skip(self))]
+    pub fn set_metadata(&mut self, key: String, value: String) -> Option<String> {
+        self.metadata.insert(key, value)
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub fn get_metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).map(String::as_str)
+    }
+
+    /// Every label attached with [LogGroup::set_metadata]
+    #[instrument(level = "trace", skip(self))]
+    pub fn metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+
+    /// A hash of the template's token kinds and literal words for cheap equality checks. Groups with
+    /// the same template share a fingerprint whichever drain or process built them, since like
+    /// [Record::normalized_hash] it never looks at symbol ids.
+    #[instrument(level = "trace", skip(self))]
+    pub fn fingerprint(&self) -> u64 {
+        self.event
+            .normalized_hash_extended(&[u8::from(self.optional_tail)])
+    }
+
+    /// Whether the last token of the template may be absent, see [LogGroup::merge]
+    #[instrument(level = "trace", skip(self))]
+    pub fn is_tail_optional(&self) -> bool {
+        self.optional_tail
+    }
+
+    /// Append the current template to the history if it differs from the latest revision
+    fn record_template(&mut self) {
+        let template = self.template();
+        if self.template_history.last().map(|(_, t)| t) != Some(&template) {
+            self.template_history.push((Utc::now(), template));
+        }
+    }
+
+    /// Every revision of the template with the time it took effect, oldest first. The first entry
+    /// is the template the group was created with and a new one is added each time discovered
+    /// variables change it. Groups deserialized from before history was kept start out empty.
+    #[instrument(level = "trace", skip(self))]
+    pub fn template_history(&self) -> &[(DateTime<Utc>, String)] {
+        &self.template_history
+    }
+
+    /// Recompute the variable map from scratch by comparing every stored example against the base
+    /// event at once, rather than incrementally as examples are added. Positions already replaced
+    /// by a wildcard in the base event stay variable since their original value is gone.
+    #[instrument(level = "trace", skip(self))]
+    pub fn rediscover_all_variables(&mut self) {
+        self.variables.clear();
+        self.numeric.clear();
+        let mut vars = vec![];
+        for (idx, token) in self.event.borrow().into_iter().enumerate() {
+            let values = self
+                .examples
+                .iter()
+                .filter_map(|rec| rec.inner.get_token_at_index(idx))
+                .collect::<Vec<Token>>();
+            if token != Token::Wildcard && values.iter().all(|v| *v == token) {
+                continue;
+            }
+            if self.coalesce_numeric {
+                let numeric = values
+                    .iter()
+                    .chain(Some(&token).filter(|t| **t != Token::Wildcard))
+                    .all(|t| GrokSet::new(&t.to_string()).is_numeric());
+                if numeric && !values.is_empty() {
+                    self.numeric.insert(idx);
+                }
+            }
+            vars.push(Wildcard((idx, Token::Wildcard)));
+        }
+        self.update_variables(vars);
+    }
+
+    /// Record which of the newly discovered variables are numeric on both sides of the comparison
+    #[instrument(level = "trace", skip(self, vars, rec))]
+    fn mark_numeric(&mut self, vars: &[Wildcard], rec: &Record) {
+        for var in vars {
+            let idx = var.0 .0;
+            let numeric = [&self.event, rec].iter().all(|r| {
+                r.inner
+                    .get_token_at_index(idx)
+                    .is_some_and(|t| GrokSet::new(&t.to_string()).is_numeric())
+            });
+            if numeric {
+                self.numeric.insert(idx);
+            }
+        }
+    }
+
+    /// Add the values `rec` holds at variable positions to their sketches. A newly discovered
+    /// variable is first seeded with the literal every earlier line had there, and a position
+    /// stops being tracked once it holds something other than a number.
+    fn track_values(&mut self, rec: &Record, vars: &[Wildcard]) {
+        let known = self.variables.keys().copied().collect::<Vec<usize>>();
+        for idx in known.into_iter().chain(vars.iter().map(|var| var.0 .0)) {
+            if self.non_numeric.contains(&idx) {
+                continue;
+            }
+            let number = |r: &Record| {
+                r.inner
+                    .get_token_at_index(idx)
+                    .and_then(|t| t.to_string().parse::<f64>().ok())
+                    .filter(|v| v.is_finite())
+            };
+            let seed = if self.variables.contains_key(&idx) {
+                None
+            } else {
+                Some(number(&self.event))
+            };
+            match (number(rec), seed) {
+                (Some(value), None) => {
+                    self.value_sketches
+                        .entry(idx)
+                        .or_default()
+                        .insert_n(value, 1);
+                },
+                (Some(value), Some(Some(literal))) => {
+                    let sketch = self.value_sketches.entry(idx).or_default();
+                    sketch.insert_n(literal, self.count);
+                    sketch.insert_n(value, 1);
+                },
+                _ => {
+                    self.value_sketches.remove(&idx);
+                    self.non_numeric.insert(idx);
+                },
+            }
+        }
+    }
+
+    /// Approximate `p`th percentile, from 0 to 100, of the values seen at variable position `idx`,
+    /// accurate to within 1% of the true value. Only positions which have held nothing but numbers
+    /// since becoming variable are tracked, for anything else this is `None`.
+    #[instrument(level = "trace", skip(self))]
+    pub fn percentile(&self, idx: usize, p: f64) -> Option<f64> {
+        self.value_sketches.get(&idx)?.quantile(p / 100.0)
+    }
+
+    /// Returns the indices of the variable positions in ascending order
+    #[instrument(level = "trace", skip_all)]
+    pub fn variable_positions(&self) -> Vec<usize> {
+        let mut positions = self.variables.keys().copied().collect::<Vec<usize>>();
+        positions.sort_unstable();
+        positions
+    }
+
+    /// Returns the variable positions as sorted `[start, end)` spans. Unless numeric coalescing
+    /// is enabled every span covers a single position.
+    #[instrument(level = "trace", skip_all)]
+    pub fn variable_spans(&self) -> Vec<(usize, usize)> {
+        let positions = self.variable_positions();
+        let mut spans: Vec<(usize, usize)> = Vec::with_capacity(positions.len());
+        for idx in positions {
+            match spans.last_mut() {
+                Some((_, end))
+                    if *end == idx
+                        && self.numeric.contains(&idx)
+                        && self.numeric.contains(&(idx - 1)) =>
+                {
+                    *end = idx + 1;
+                },
+                _ => spans.push((idx, idx + 1)),
+            }
+        }
+        spans
+    }
+
+    /// Render the template of this group, with each variable span shown as a single wildcard
+    #[instrument(level = "trace", skip_all)]
+    pub fn template(&self) -> String {
+        let mut template = self
+            .event
+            .inner
+            .collapse_spans(&self.variable_spans())
+            .to_string();
+        if self.optional_tail {
+            template.push('?');
+        }
+        template
+    }
+
+    /// Render the template as a Grok pattern for Logstash or Elastic ingest pipelines. Literal
+    /// words are escaped, typed tokens become their Grok equivalent and variables become the
+    /// Grok equivalent of the type every value seen at that position shares, or `%{DATA}` when
+    /// they don't share one. An optional trailing token becomes an optional group. Spacing between
+    /// tokens follows [LogGroup::to_regex].
+    #[instrument(level = "trace", skip(self))]
+    pub fn to_grok_pattern(&self) -> String {
+        let pieces = self
+            .event
+            .borrow()
+            .into_iter()
+            .enumerate()
+            .map(|(idx, token)| match token {
+                Token::Wildcard => format!(
+                    "%{{{}}}",
+                    self.variable_kind(idx)
+                        .map_or("DATA", Grokker::grok_pattern)
+                ),
+                Token::TypedMatch(grokker) => format!("%{{{}}}", grokker.grok_pattern()),
+                Token::Value(_) => self.literal_pattern(idx),
+            })
+            .collect::<Vec<String>>();
+        self.join_pattern(pieces, &self.separators())
+    }
+
+    /// Build an anchored regular expression matching lines of this template, for re-matching raw
+    /// lines against it. Literal words are escaped, typed tokens and variables whose values share
+    /// a type use that type's pattern and any other variable matches `\S+`. Tokens which touch in
+    /// the line which created the group must touch, any others are separated by any whitespace.
+    #[instrument(level = "trace", skip(self))]
+    pub fn to_regex(&self) -> Result<Regex, DrainError> {
+        let pieces = self
+            .event
+            .borrow()
+            .into_iter()
+            .enumerate()
+            .map(|(idx, token)| match token {
+                Token::Wildcard => self
+                    .variable_kind(idx)
+                    .map_or_else(|| r"\S+".to_string(), Grokker::to_unanchored_pattern),
+                Token::TypedMatch(grokker) => grokker.to_unanchored_pattern(),
+                Token::Value(_) => self.literal_pattern(idx),
+            })
+            .collect::<Vec<String>>();
+        let pattern = format!("^{}$", self.join_pattern(pieces, &self.separators()));
+        Ok(Regex::new(&pattern)?)
+    }
+
+    /// The escaped literal word at `idx` as it appears in the template, matching any case when the
+    /// group was built from case folded tokens
+    fn literal_pattern(&self, idx: usize) -> String {
+        let stream = &self.event.inner;
+        let (offset, token) = &stream.inner[idx];
+        let word = regex::escape(&stream.display_word(offset, token));
+        if stream.is_case_folded() {
+            format!("(?i:{})", word)
+        } else {
+            word
+        }
+    }
+
+    /// Pattern between each token of the template and the next, nothing where they touch in the
+    /// line which created the group and a run of whitespace elsewhere
+    fn separators(&self) -> Vec<&'static str> {
+        self.event
+            .inner
+            .inner
+            .windows(2)
+            .map(|pair| {
+                if pair[0].0.end() == pair[1].0.start() {
+                    ""
+                } else {
+                    r"\s+"
+                }
+            })
+            .collect()
+    }
+
+    /// Join rendered positions with `separators`, making the last one optional for an optional
+    /// tail
+    fn join_pattern(&self, pieces: Vec<String>, separators: &[&str]) -> String {
+        let last = pieces.len().saturating_sub(1);
+        let mut pattern = String::new();
+        for (idx, piece) in pieces.into_iter().enumerate() {
+            let separator = idx.checked_sub(1).map_or("", |prev| separators[prev]);
+            if idx == last && idx > 0 && self.optional_tail {
+                pattern.push_str(&format!("(?:{}{})?", separator, piece));
+            } else {
+                pattern.push_str(separator);
+                pattern.push_str(&piece);
+            }
+        }
+        pattern
+    }
+
+    /// The type shared by every value seen at variable position `idx`, if they share one
+    fn variable_kind(&self, idx: usize) -> Option<Grokker> {
+        let mut kinds = self
+            .origin
+            .iter()
+            .chain(self.examples.iter())
+            .filter(|rec| rec.len() == self.event.len())
+            .filter_map(|rec| rec.inner.get_token_at_index(idx))
+            .map(|value| Token::from_parse(&value.to_string()));
+        match kinds.next() {
+            Some(Token::TypedMatch(first)) if kinds.all(|k| k == Token::TypedMatch(first)) => {
+                Some(first)
+            },
+            _ => None,
+        }
+    }
+
+    /// Render the template with the values of the example at `example_idx` in place of each
+    /// wildcard, keeping that example's spacing. Returns `None` if there is no such example or it
+    /// was aligned into the group with a different number of tokens.
+    #[instrument(level = "trace", skip(self))]
+    pub fn render_with_example(&self, example_idx: usize) -> Option<String> {
+        let example = self.examples.get(example_idx)?;
+        if example.len() != self.event.len() {
+            return None;
+        }
+        let mut stream = example.inner.clone();
+        for (idx, (_, token)) in stream.inner.iter_mut().enumerate() {
+            if !self.variables.contains_key(&idx) {
+                *token = self.event.inner.inner[idx].1.clone();
+            }
+        }
+        Some(stream.to_string())
     }
 
     /// Number of examples this [LogGroup] contains
@@ -102,18 +688,72 @@ impl LogGroup {
         self.examples.len()
     }
 
+    /// Number of lines this [LogGroup] has absorbed, including the one which created it
+    #[instrument(level = "trace", skip_all)]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
     /// Whether any examples exist for a [LogGroup]
     #[instrument(level = "trace", skip_all)]
     pub fn is_empty(&self) -> bool {
         self.examples.is_empty()
     }
 
+    /// The record which created this group, before any of its tokens were replaced by wildcards.
+    /// Only `None` for groups deserialized from a version which didn't retain it.
+    #[instrument(level = "trace", skip_all)]
+    pub fn origin(&self) -> Option<&Record> {
+        self.origin.as_ref()
+    }
+
     /// Return a Vec<&Record> of the example records for this group
     #[instrument(level = "trace", skip_all)]
     pub fn get_examples(&self) -> Vec<&Record> {
         self.examples.iter().collect::<Vec<&Record>>()
     }
 
+    /// Iterate over the example records for this group without collecting them
+    #[instrument(level = "trace", skip_all)]
+    pub fn examples_iter(&self) -> impl Iterator<Item = &Record> {
+        self.examples.iter()
+    }
+
+    /// Return the example records for which `pred` returns true
+    #[instrument(level = "trace", skip_all)]
+    pub fn examples_where(&self, pred: impl Fn(&Record) -> bool) -> Vec<&Record> {
+        self.examples
+            .iter()
+            .filter(|r| pred(r))
+            .collect::<Vec<&Record>>()
+    }
+
+    /// Shannon entropy in bits of the values seen at position `idx` across the examples. Values
+    /// near zero suggest the position holds few distinct values and may have been over-generalized.
+    /// Returns `None` if `idx` is out of range or there are no examples.
+    #[instrument(level = "trace", skip(self))]
+    pub fn position_entropy(&self, idx: usize) -> Option<f64> {
+        if idx >= self.event.len() || self.examples.is_empty() {
+            return None;
+        }
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for rec in &self.examples {
+            if let Some(token) = rec.inner.get_token_at_index(idx) {
+                *counts.entry(token.to_string()).or_insert(0) += 1;
+            }
+        }
+        let total = counts.values().sum::<usize>() as f64;
+        Some(
+            counts
+                .values()
+                .map(|&n| {
+                    let p = n as f64 / total;
+                    -p * p.log2()
+                })
+                .sum(),
+        )
+    }
+
     /// Returns the [Ksuid] associated with the [LogGroup], usually identical to the [Record] which created the group
     #[instrument(level = "trace", skip_all)]
     pub fn get_id(&self) -> Ksuid {
@@ -125,16 +765,67 @@ impl LogGroup {
     pub fn get_time(&self) -> DateTime<Utc> {
         self.event.uid.get_time()
     }
+
+    /// Orders groups by creation, unlike [LogGroup::get_time] which can't separate groups created
+    /// in the same second
+    #[instrument(level = "trace", skip_all)]
+    pub(crate) fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Number of consecutive examples added without discovering a new variable
+    #[instrument(level = "trace", skip_all)]
+    pub fn stable_count(&self) -> usize {
+        self.stable_count
+    }
+
+    /// Whether the template has gone at least `min_stable` examples without changing
+    #[instrument(level = "trace", skip(self))]
+    pub fn is_stable(&self, min_stable: usize) -> bool {
+        self.stable_count >= min_stable
+    }
+
+    /// Returns the [DateTime] this [LogGroup] was created or last had an example added. Groups
+    /// saved before this was recorded report their creation time until they gain an example.
+    #[instrument(level = "trace", skip_all)]
+    pub fn last_seen(&self) -> DateTime<Utc> {
+        self.last_seen.unwrap_or_else(|| self.get_time())
+    }
+}
+
+/// Tokenize `lines` and return their shared template with every differing position replaced by a
+/// wildcard. Returns `None` if there are no lines or they don't all have the same number of tokens.
+#[instrument(level = "trace", skip_all)]
+pub fn common_template(lines: &[String]) -> Option<String> {
+    let mut records = lines.iter().map(|l| Record::new(l.clone()));
+    let mut group = LogGroup::new(records.next()?);
+    for rec in records {
+        if rec.len() != group.event().len() {
+            return None;
+        }
+        group.add_example(rec);
+    }
+    Some(group.template())
+}
+
+/// One step of the SplitMix64 generator, used to pick reservoir slots without a random number crate
+fn splitmix64(state: u64) -> u64 {
+    let mut z = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
 }
 
 impl fmt::Display for LogGroup {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "LogGroup ID: {}\nFirst Seen: {}\nEvent: {}\n{} examples and {} wildcards\n",
+            "LogGroup ID: {}\nFirst Seen: {}\nLast Seen: {}\nEvent: {}\n{} examples and {} \
+             wildcards\n",
             self.event.uid.serialize(),
             self.event.uid.get_time(),
-            self.event,
+            self.last_seen(),
+            self.template(),
             self.examples.len(),
             self.variables.len()
         )
@@ -147,8 +838,12 @@ mod should {
 
     use super::Wildcard;
     use crate::{
-        log_group::LogGroup,
-        record::{tokens::Token, Record},
+        error::DrainError,
+        log_group::{common_template, ExamplePolicy, LogGroup},
+        record::{
+            tokens::{Token, TokenizerOptions},
+            Record,
+        },
     };
 
     #[test]
@@ -170,4 +865,393 @@ mod should {
         lg.update_variables(vars);
         assert_that(&lg.variables).contains_key(6);
     }
+
+    #[test]
+    fn test_to_grok_pattern() {
+        let mut lg = LogGroup::new(Record::new(
+            "Connection from 10.0.0.1 port 22 by alice (ok)".to_string(),
+        ));
+        lg.add_example(Record::new(
+            "Connection from 10.0.0.2 port 2222 by bob (ok)".to_string(),
+        ));
+        assert_that!(lg.to_grok_pattern()).is_equal_to(
+            r"Connection\s+from\s+%{IP}\s+port\s+%{INT}\s+by\s+%{DATA}\s+\(ok\)".to_string(),
+        );
+        let options = TokenizerOptions {
+            split_pid: true,
+            ..TokenizerOptions::default()
+        };
+        let mut split = LogGroup::new(Record::with_options(
+            "sshd[4567]: session opened".to_string(),
+            &options,
+        ));
+        split.add_example(Record::with_options(
+            "sshd[81]: session opened".to_string(),
+            &options,
+        ));
+        assert_that!(split.to_grok_pattern())
+            .is_equal_to(r"sshd\[%{INT}\]:\s+session\s+opened".to_string());
+    }
+
+    #[test]
+    fn test_to_regex() {
+        let mut lg = LogGroup::new(Record::new(
+            "Connection from 10.0.0.1 port 22 by alice (ok)".to_string(),
+        ));
+        lg.add_example(Record::new(
+            "Connection from 10.0.0.2 port 2222 by bob (ok)".to_string(),
+        ));
+        let re = lg.to_regex().unwrap();
+        assert_that!(re.is_match("Connection from 10.0.0.1 port 22 by alice (ok)")).is_true();
+        assert_that!(re.is_match("Connection from 192.168.1.9 port 80 by carol (ok)")).is_true();
+        assert_that!(re.is_match("Connection from nowhere port 22 by alice (ok)")).is_false();
+        assert_that!(re.is_match("Disk sda1 is 91% full")).is_false();
+    }
+
+    #[test]
+    fn test_to_regex_split_tokens() {
+        let options = TokenizerOptions {
+            split_pid: true,
+            split_key_value: true,
+            ..TokenizerOptions::default()
+        };
+        let line = "Nov  5 sshd[4567]: session status=opened";
+        let mut lg = LogGroup::new(Record::with_options(line.to_string(), &options));
+        lg.add_example(Record::with_options(
+            "Nov  5 sshd[81]: session status=closed".to_string(),
+            &options,
+        ));
+        let re = lg.to_regex().unwrap();
+        assert_that!(re.is_match(line)).is_true();
+        assert_that!(re.is_match("Nov 5 sshd[12]: session status=failed")).is_true();
+        assert_that!(re.is_match("Nov 5 sshd [12]: session status=failed")).is_false();
+    }
+
+    #[test]
+    fn test_percentile() {
+        let mut lg = LogGroup::new(Record::new("Query took 1 ms on users".to_string()));
+        for ms in 2..=1000 {
+            lg.add_example(Record::new(format!("Query took {} ms on users", ms)));
+        }
+        let p95 = lg.percentile(2, 95.0).unwrap();
+        assert_that!((p95 - 950.0).abs()).is_less_than(950.0 * 0.01);
+        assert_that!(lg.percentile(2, 50.0).unwrap()).is_greater_than(490.0);
+        assert_that!(lg.percentile(4, 50.0)).is_none();
+        let mut merged = lg.clone();
+        lg.add_example(Record::new("Query took slow ms on users".to_string()));
+        assert_that!(lg.percentile(2, 95.0)).is_none();
+        merged.merge(lg).unwrap();
+        assert_that!(merged.percentile(2, 95.0)).is_none();
+    }
+
+    #[test]
+    fn test_example_policy() {
+        let lines = (0..10)
+            .map(|i| format!("Job {} finished", i))
+            .collect::<Vec<String>>();
+        let kept = |policy| {
+            let mut lg = LogGroup::new(Record::new("Job started finished".to_string()));
+            lg.set_example_policy(policy);
+            for line in &lines {
+                lg.add_example(Record::new(line.clone()));
+            }
+            assert_that!(lg.count()).is_equal_to(11);
+            lg.examples_iter()
+                .map(ToString::to_string)
+                .collect::<Vec<String>>()
+        };
+        assert_that!(kept(ExamplePolicy::All)).is_equal_to(lines.clone());
+        assert_that!(kept(ExamplePolicy::FirstN(3))).is_equal_to(lines[..3].to_vec());
+        assert_that!(kept(ExamplePolicy::LastN(3))).is_equal_to(lines[7..].to_vec());
+        let sample = kept(ExamplePolicy::Reservoir(3));
+        assert_that!(sample.len()).is_equal_to(3);
+        assert!(sample.iter().all(|line| lines.contains(line)));
+        assert_that!(kept(ExamplePolicy::Reservoir(3))).is_equal_to(sample);
+    }
+
+    #[test]
+    fn test_merge_across_lengths() {
+        let mut short = LogGroup::new(Record::new("User alice logged in from web".to_string()));
+        let long = LogGroup::new(Record::new("User bob logged in from web app".to_string()));
+        short.merge(long).unwrap();
+        assert_that!(short.template()).is_equal_to("User * logged in from web *?".to_string());
+        assert_that!(short.is_tail_optional()).is_true();
+        assert_that!(short.count()).is_equal_to(2);
+        assert_that!(short.examples_iter().count()).is_equal_to(1);
+
+        let mut long = LogGroup::new(Record::new("Disk sda1 is 91% full now".to_string()));
+        let short = LogGroup::new(Record::new("Disk sda1 is 91% full".to_string()));
+        long.merge(short).unwrap();
+        assert_that!(long.template()).is_equal_to("Disk sda1 is 91% full *?".to_string());
+
+        let far = LogGroup::new(Record::new("Disk".to_string()));
+        assert!(matches!(
+            long.merge(far),
+            Err(DrainError::LengthMismatch { left: 6, right: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_examples_iter() {
+        let mut lg = LogGroup::new(Record::new("Job 17 finished".to_string()));
+        lg.add_example(Record::new("Job 18 finished".to_string()));
+        lg.add_example(Record::new("Job 19 finished".to_string()));
+        let lazy = lg.examples_iter().collect::<Vec<&Record>>();
+        assert_that!(lazy.len()).is_equal_to(2);
+        assert!(lazy
+            .iter()
+            .zip(lg.get_examples())
+            .all(|(a, b)| std::ptr::eq(*a, b)));
+    }
+
+    #[test]
+    fn test_template_history() {
+        let mut lg = LogGroup::new(Record::new("Job 17 on worker-a finished".to_string()));
+        lg.add_example(Record::new("Job 18 on worker-a finished".to_string()));
+        lg.add_example(Record::new("Job 19 on worker-a finished".to_string()));
+        assert_that!(lg.template_history().len()).is_equal_to(2);
+        lg.add_example(Record::new("Job 20 on worker-b finished".to_string()));
+        let history = lg
+            .template_history()
+            .iter()
+            .map(|(_, t)| t.as_str())
+            .collect::<Vec<_>>();
+        assert_that!(history).is_equal_to(vec![
+            "Job 17 on worker-a finished",
+            "Job * on worker-a finished",
+            "Job * on * finished",
+        ]);
+        assert!(lg
+            .template_history()
+            .windows(2)
+            .all(|pair| pair[0].0 <= pair[1].0));
+    }
+
+    #[test]
+    fn test_rediscover_all_variables() {
+        let lines = [
+            "Job 17 on worker-a finished in 120 ms",
+            "Job 18 on worker-a finished in 95 ms",
+            "Job 19 on worker-b finished in 95 ms",
+        ];
+        let mut incremental = LogGroup::new(Record::new(lines[0].to_string()));
+        let mut batch = LogGroup::new(Record::new(lines[0].to_string()));
+        for line in &lines[1..] {
+            incremental.add_example(Record::new(line.to_string()));
+            batch.examples.push_back(Record::new(line.to_string()));
+        }
+        assert_that!(batch.variables).is_empty();
+        batch.rediscover_all_variables();
+        let mut expected = incremental.variables.keys().collect::<Vec<_>>();
+        let mut found = batch.variables.keys().collect::<Vec<_>>();
+        expected.sort_unstable();
+        found.sort_unstable();
+        assert_that!(found).is_equal_to(expected);
+        assert_that!(batch.template()).is_equal_to(incremental.template());
+    }
+
+    #[test]
+    fn test_variable_positions_sorted() {
+        let mut lg = LogGroup::new(Record::new("a 1 b 2 c 3 d 4 e 5".to_string()));
+        lg.add_example(Record::new("f 6 b 7 g 8 d 9 h 0".to_string()));
+        let positions = lg.variable_positions();
+        assert_that!(positions).is_equal_to(vec![0, 1, 3, 4, 5, 7, 8, 9]);
+    }
+
+    #[test]
+    fn test_add_aligned_example() {
+        let mut lg = LogGroup::new(Record::new(
+            "user alice logged in from 10.0.0.1".to_string(),
+        ));
+        lg.add_aligned_example(Record::new(
+            "user bob has logged in from 10.0.0.1".to_string(),
+        ));
+        assert_that(&lg.template()).is_equal_to("user * logged in from 10.0.0.1".to_string());
+        assert_that(&lg.count()).is_equal_to(2);
+    }
+
+    #[test]
+    fn test_render_with_example() {
+        let mut lg = LogGroup::new(Record::new("Disk sda1 is 91% full".to_string()));
+        for line in ["Disk sdb2 is  97% full", "Disk sdc3 is 12% full"] {
+            lg.add_example(Record::new(line.to_string()));
+        }
+        assert_that(&lg.template()).is_equal_to("Disk * is * full".to_string());
+        assert_that(&lg.render_with_example(0)).is_equal_to(Some("Disk sdb2 is  97% full".into()));
+        assert_that(&lg.render_with_example(1)).is_equal_to(Some("Disk sdc3 is 12% full".into()));
+        assert_that(&lg.render_with_example(2)).is_none();
+    }
+
+    #[test]
+    fn test_coalesce_numeric_span() {
+        let r1 = Record::new("Counters reset to 1 2 3 4".to_string());
+        let r2 = Record::new("Counters reset to 5 6 7 8".to_string());
+        let mut lg = LogGroup::new(r1);
+        lg.set_coalesce_numeric(true);
+        lg.add_example(r2);
+        assert_that(&lg.variables).has_length(4);
+        assert_that(&lg.variable_spans()).is_equal_to(vec![(3, 7)]);
+        assert_that(&lg.template()).is_equal_to("Counters reset to *".to_string());
+    }
+
+    #[test]
+    fn test_no_coalesce_by_default() {
+        let r1 = Record::new("Counters reset to 1 2 3 4".to_string());
+        let r2 = Record::new("Counters reset to 5 6 7 8".to_string());
+        let mut lg = LogGroup::new(r1);
+        lg.add_example(r2);
+        assert_that(&lg.variable_spans()).has_length(4);
+        assert_that(&lg.template()).is_equal_to("Counters reset to * * * *".to_string());
+    }
+
+    #[test]
+    fn test_last_seen_advances() {
+        let r1 = Record::new("Common Prefix Common Prefix Common Prefix 6789".to_string());
+        let r2 = Record::new("Common Prefix Common Prefix Common Prefix 827364".to_string());
+        let mut lg = LogGroup::new(r1);
+        let created = lg.last_seen();
+        assert_that(&created).is_greater_than_or_equal_to(lg.get_time());
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        lg.add_example(r2);
+        assert_that(&lg.last_seen()).is_greater_than(created);
+    }
+
+    #[test]
+    fn test_examples_where() {
+        let base = Record::new("Message send failed to remote host: foo".to_string());
+        let mut lg = LogGroup::new(base);
+        for host in ["bar", "baz", "bar"] {
+            lg.add_example(Record::new(format!(
+                "Message send failed to remote host: {}",
+                host
+            )));
+        }
+        let bars =
+            lg.examples_where(|r| r.into_iter().nth(6).is_some_and(|t| t.to_string() == "bar"));
+        assert_that(&bars).has_length(2);
+        assert_that(&lg.examples_where(|_| false)).is_empty();
+    }
+
+    #[test]
+    fn test_position_entropy() {
+        let mut lg = LogGroup::new(Record::new(
+            "user alice logged in from 10.0.0.1".to_string(),
+        ));
+        for i in 0..16 {
+            lg.add_example(Record::new(format!("user bob logged in from 10.0.0.{}", i)));
+        }
+        let repeated = lg.position_entropy(1).unwrap();
+        let distinct = lg.position_entropy(5).unwrap();
+        assert_that!(repeated).is_less_than(0.001);
+        assert_that!(distinct).is_greater_than(3.999);
+        assert_that!(lg.position_entropy(6)).is_none();
+    }
+
+    #[test]
+    fn test_stability() {
+        let mut lg = LogGroup::new(Record::new("Job 17 finished in 250 ms".to_string()));
+        lg.add_example(Record::new("Job 18 finished in 250 ms".to_string()));
+        assert_that(&lg.is_stable(1)).is_false();
+        for _ in 0..3 {
+            lg.add_example(Record::new("Job 19 finished in 250 ms".to_string()));
+        }
+        assert_that(&lg.is_stable(3)).is_true();
+        assert_that(&lg.is_stable(4)).is_false();
+        // a new variable resets the count
+        lg.add_example(Record::new("Job 20 finished in 300 ms".to_string()));
+        assert_that(&lg.stable_count()).is_equal_to(0);
+        assert_that(&lg.is_stable(1)).is_false();
+    }
+
+    #[test]
+    fn test_common_template() {
+        let lines = [
+            "Disk sda1 is 91% full on db-01",
+            "Disk sda1 is 95% full on db-02",
+            "Disk sdb1 is 91% full on db-01",
+        ]
+        .map(String::from);
+        assert_that(&common_template(&lines))
+            .is_equal_to(Some("Disk * is * full on *".to_string()));
+        let mismatched = [
+            "Disk sda1 is 91% full".to_string(),
+            "Disk sda1 full".to_string(),
+        ];
+        assert_that(&common_template(&mismatched)).is_none();
+        assert_that(&common_template(&[])).is_none();
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let r1 = Record::new("Connection from 10.0.0.1 closed after 12 seconds".to_string());
+        let r2 = Record::new("Connection from 10.0.0.2 closed after 97 seconds".to_string());
+        let mut lg = LogGroup::new(r1);
+        lg.add_example(r2);
+        let json = serde_json::to_string(&lg).unwrap();
+        let restored: LogGroup = serde_json::from_str(&json).unwrap();
+        assert_that(&restored.get_id()).is_equal_to(lg.get_id());
+        assert_that(&restored.template()).is_equal_to(lg.template());
+        assert_that(&restored.variables).is_equal_to(lg.variables.clone());
+        assert_that(&restored.len()).is_equal_to(1);
+        assert_that(&restored.get_examples()[0].to_string())
+            .is_equal_to(lg.get_examples()[0].to_string());
+    }
+
+    #[test]
+    fn test_sequence_survives_reload() {
+        let first = LogGroup::new(Record::new("Worker 1 started".to_string()));
+        let second = LogGroup::new(Record::new("Worker 2 started".to_string()));
+        assert_that(&second.sequence()).is_greater_than(first.sequence());
+        let mut json = serde_json::to_value(&second).unwrap();
+        json["sequence"] = (second.sequence() + 1000).into();
+        let restored: LogGroup = serde_json::from_value(json).unwrap();
+        let later = LogGroup::new(Record::new("Worker 3 started".to_string()));
+        assert_that(&later.sequence()).is_greater_than(restored.sequence());
+    }
+
+    #[test]
+    fn test_deserialize_older_groups() {
+        let lg = LogGroup::new(Record::new("Worker 7 stopped".to_string()));
+        let mut json = serde_json::to_value(&lg).unwrap();
+        let fields = json.as_object_mut().unwrap();
+        fields.remove("last_seen");
+        fields.remove("stable_count");
+        fields.remove("count");
+        let restored: LogGroup = serde_json::from_value(json).unwrap();
+        assert_that(&restored.last_seen()).is_equal_to(lg.get_time());
+        assert_that(&restored.stable_count()).is_equal_to(0);
+        assert_that(&restored.count()).is_equal_to(1);
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let mut a = LogGroup::new(Record::new("Disk sda1 is 91% full".to_string()));
+        a.add_example(Record::new("Disk sdb2 is 45% full".to_string()));
+        let mut b = LogGroup::new(Record::new("Disk nvme0 is 12% full".to_string()));
+        b.add_example(Record::new("Disk nvme1 is 80% full".to_string()));
+        let c = LogGroup::new(Record::new("Disk sda1 is 91% full".to_string()));
+        assert_that(&a.template()).is_equal_to(b.template());
+        assert_that(&a.fingerprint()).is_equal_to(b.fingerprint());
+        assert_that(&a.fingerprint()).is_not_equal_to(c.fingerprint());
+        let template = LogGroup::from_template(Record::new("Disk * is * full".to_string()));
+        assert_that(&template.fingerprint()).is_equal_to(a.fingerprint());
+        let mut tail = a.clone();
+        tail.optional_tail = true;
+        assert_that(&tail.fingerprint()).is_not_equal_to(a.fingerprint());
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let mut lg = LogGroup::new(Record::new("Heartbeat from node-3 ok".to_string()));
+        assert_that(&lg.set_metadata("status".to_string(), "noise".to_string())).is_none();
+        assert_that(&lg.set_metadata("status".to_string(), "known noise".to_string()))
+            .is_equal_to(Some("noise".to_string()));
+        lg.set_metadata("owner".to_string(), "platform".to_string());
+        let json = serde_json::to_string(&lg).unwrap();
+        let restored: LogGroup = serde_json::from_str(&json).unwrap();
+        assert_that(&restored.get_metadata("status")).is_equal_to(Some("known noise"));
+        assert_that(&restored.get_metadata("owner")).is_equal_to(Some("platform"));
+        assert_that(&restored.get_metadata("missing")).is_none();
+        assert_that(restored.metadata()).is_equal_to(lg.metadata());
+    }
 }
@@ -0,0 +1,117 @@
+// Copyright Nicholas Harring. All rights reserved.
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the Server Side Public License, version 1, as published by MongoDB, Inc.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the Server Side Public License for more details. You should have received a copy of the
+// Server Side Public License along with this program.
+// If not, see <http://www.mongodb.com/licensing/server-side-public-license>.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+/// Streaming quantile estimates for a numeric variable, accurate to within 1% of the true value.
+/// Values are counted in buckets whose bounds grow geometrically, so memory grows with the
+/// logarithm of the range of values seen rather than with how many were seen.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct QuantileSketch {
+    // bucket -> count, for values above zero and for the magnitude of values below zero
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zeros: u64,
+    count: u64,
+}
+
+impl QuantileSketch {
+    /// Relative error of estimates
+    const ACCURACY: f64 = 0.01;
+
+    /// Ratio between the bounds of consecutive buckets
+    fn gamma() -> f64 {
+        (1.0 + Self::ACCURACY) / (1.0 - Self::ACCURACY)
+    }
+
+    fn bucket(magnitude: f64) -> i32 {
+        (magnitude.ln() / Self::gamma().ln()).ceil() as i32
+    }
+
+    /// Value within 1% of everything counted in `bucket`
+    fn estimate(bucket: i32) -> f64 {
+        2.0 * Self::gamma().powi(bucket) / (Self::gamma() + 1.0)
+    }
+
+    /// Count `value` `n` times, ignoring infinities and NaN
+    #[instrument(level = "trace", skip(self))]
+    pub(crate) fn insert_n(&mut self, value: f64, n: u64) {
+        if !value.is_finite() || n == 0 {
+            return;
+        }
+        if value.abs() < f64::MIN_POSITIVE {
+            self.zeros += n;
+        } else if value > 0.0 {
+            *self.positive.entry(Self::bucket(value)).or_default() += n;
+        } else {
+            *self.negative.entry(Self::bucket(-value)).or_default() += n;
+        }
+        self.count += n;
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    pub(crate) fn merge(&mut self, other: &QuantileSketch) {
+        for (bucket, n) in &other.positive {
+            *self.positive.entry(*bucket).or_default() += n;
+        }
+        for (bucket, n) in &other.negative {
+            *self.negative.entry(*bucket).or_default() += n;
+        }
+        self.zeros += other.zeros;
+        self.count += other.count;
+    }
+
+    /// Estimate the `q` quantile, between 0 and 1, or `None` if nothing has been counted
+    #[instrument(level = "trace", skip(self))]
+    pub(crate) fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 || !(0.0..=1.0).contains(&q) {
+            return None;
+        }
+        let rank = (q * (self.count - 1) as f64).round() as u64;
+        let mut seen = 0;
+        for (bucket, n) in self.negative.iter().rev() {
+            seen += n;
+            if seen > rank {
+                return Some(-Self::estimate(*bucket));
+            }
+        }
+        seen += self.zeros;
+        if seen > rank {
+            return Some(0.0);
+        }
+        self.positive.iter().find_map(|(bucket, n)| {
+            seen += n;
+            (seen > rank).then(|| Self::estimate(*bucket))
+        })
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use spectral::prelude::*;
+
+    use super::QuantileSketch;
+
+    #[test]
+    fn test_quantiles_within_accuracy() {
+        let mut sketch = QuantileSketch::default();
+        for v in -500..=1000 {
+            sketch.insert_n(f64::from(v), 1);
+        }
+        for (q, expected) in [(0.0, -500.0), (0.5, 250.0), (0.99, 985.0)] {
+            let estimate = sketch.quantile(q).unwrap();
+            assert_that!((estimate - expected).abs()).is_less_than(f64::abs(expected) * 0.01);
+        }
+        assert_that!(sketch.quantile(1.5)).is_none();
+    }
+}
@@ -11,22 +11,50 @@
 pub mod tokens;
 extern crate derive_more;
 
-use std::fmt;
+use std::{collections::HashSet, fmt};
 
 use lazy_static::lazy_static;
+use parking_lot::{MappedRwLockReadGuard, RwLockReadGuard};
 use rksuid::Ksuid;
+use serde::{Deserialize, Serialize};
 use string_interner::DefaultSymbol;
 use tracing::{debug, instrument};
 
-use self::tokens::{Token, TokenStream, TypedToken};
+use self::tokens::{Grokker, Token, TokenStream, TokenizerOptions, TypedToken};
 use crate::drains::simple::INTERNER;
 
 lazy_static! {
     static ref ASTERISK: DefaultSymbol = INTERNER.write().get_or_intern_static("*");
 }
-#[derive(Clone, Debug)]
+/// How much each position counts towards [Record::calc_position_weighted_score]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PositionWeighting {
+    /// Every position counts the same, equivalent to [Record::calc_sim_score]
+    #[default]
+    Uniform,
+    /// The weight grows by one per position, so the last token counts `len` times the first
+    Linear,
+    /// The weight grows exponentially to about 55 times the first at the last token, whatever the
+    /// length of the line
+    Exponential,
+}
+
+impl PositionWeighting {
+    /// Weight of position `idx` in a record of `len` tokens
+    #[must_use]
+    pub fn weight(self, idx: usize, len: usize) -> f64 {
+        match self {
+            PositionWeighting::Uniform => 1.0,
+            PositionWeighting::Linear => (idx + 1) as f64,
+            PositionWeighting::Exponential => (4.0 * idx as f64 / len.max(1) as f64).exp(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Record {
     pub(crate) inner: TokenStream,
+    #[serde(with = "crate::serialize::ksuid")]
     pub uid: Ksuid,
 }
 impl Record {
@@ -38,6 +66,15 @@ impl Record {
         }
     }
 
+    /// Tokenize `line` with the refinements enabled in `options`
+    #[instrument(level = "trace", skip(line))]
+    pub fn with_options(line: String, options: &TokenizerOptions) -> Self {
+        Self {
+            inner: TokenStream::from_unicode_line_with(&line, options),
+            uid: Ksuid::new(),
+        }
+    }
+
     #[instrument(
         name = "Calculate similarity score",
         level = "trace",
@@ -62,11 +99,132 @@ impl Record {
         score
     }
 
+    /// Score like [Record::calc_sim_score] but only if the score exceeds `floor`, giving up as soon
+    /// as the positions left to compare can no longer lift the score above it
+    #[instrument(level = "trace", skip_all)]
+    pub fn calc_sim_score_above(&self, candidate: &Record, floor: u64) -> Option<u64> {
+        let mut score = 0_u64;
+        let mut remaining = self.len().min(candidate.len()) as u64;
+        for (this, other) in self.into_iter().zip(candidate.into_iter()) {
+            if score + remaining <= floor {
+                return None;
+            }
+            remaining -= 1;
+            if this == other {
+                score += 1;
+            }
+        }
+        (score > floor).then_some(score)
+    }
+
+    /// Count the positions where `eq` considers this record's token and `candidate`'s equivalent
+    #[instrument(level = "trace", skip_all)]
+    pub fn calc_sim_score_by<F: Fn(&Token, &Token) -> bool>(
+        &self,
+        candidate: &Record,
+        eq: F,
+    ) -> u64 {
+        self.into_iter()
+            .zip(candidate.into_iter())
+            .filter(|(this, other)| eq(this, other))
+            .count() as u64
+    }
+
+    /// Sum of `weight` over every position where this record and `candidate` hold equal tokens
+    #[instrument(level = "trace", skip_all)]
+    pub fn calc_weighted_sim_score<F: Fn(Token) -> f64>(
+        &self,
+        candidate: &Record,
+        weight: F,
+    ) -> f64 {
+        self.into_iter()
+            .zip(candidate.into_iter())
+            .filter(|(this, other)| this == other)
+            .map(|(this, _)| weight(this))
+            .sum()
+    }
+
+    /// Pairs of positions in this record and `candidate` holding equal tokens along their longest
+    /// common subsequence, so records of different lengths can be compared after an insertion or
+    /// deletion
+    #[instrument(level = "trace", skip_all)]
+    pub fn lcs_alignment(&self, candidate: &Record) -> Vec<(usize, usize)> {
+        let ours = self.into_iter().collect::<Vec<Token>>();
+        let theirs = candidate.into_iter().collect::<Vec<Token>>();
+        // lengths[i][j] is the length of the longest common subsequence of ours[i..] and theirs[j..]
+        let mut lengths = vec![vec![0_u32; theirs.len() + 1]; ours.len() + 1];
+        for i in (0..ours.len()).rev() {
+            for j in (0..theirs.len()).rev() {
+                lengths[i][j] = if ours[i] == theirs[j] {
+                    lengths[i + 1][j + 1] + 1
+                } else {
+                    lengths[i + 1][j].max(lengths[i][j + 1])
+                };
+            }
+        }
+        let mut pairs = Vec::with_capacity(lengths[0][0] as usize);
+        let (mut i, mut j) = (0, 0);
+        while i < ours.len() && j < theirs.len() {
+            if ours[i] == theirs[j] {
+                pairs.push((i, j));
+                i += 1;
+                j += 1;
+            } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        pairs
+    }
+
+    /// Length of the longest common subsequence of tokens, see [Record::lcs_alignment]
+    #[instrument(level = "trace", skip_all)]
+    pub fn calc_lcs_score(&self, candidate: &Record) -> u64 {
+        self.lcs_alignment(candidate).len() as u64
+    }
+
+    /// Weight of the positions where this record and `candidate` hold equal tokens as a fraction
+    /// of the total weight, with positions weighted by `weighting`. Favouring the tail helps when
+    /// lines share boilerplate prefixes and differ in their endings.
+    #[instrument(level = "trace", skip(self, candidate))]
+    pub fn calc_position_weighted_score(
+        &self,
+        candidate: &Record,
+        weighting: PositionWeighting,
+    ) -> f64 {
+        let len = self.len();
+        let total: f64 = (0..len).map(|idx| weighting.weight(idx, len)).sum();
+        if total == 0.0 {
+            return 0.0;
+        }
+        let matched: f64 = self
+            .into_iter()
+            .zip(candidate.into_iter())
+            .enumerate()
+            .filter(|(_, (this, other))| this == other)
+            .map(|(idx, _)| weighting.weight(idx, len))
+            .sum();
+        matched / total
+    }
+
     #[instrument(level = "trace", skip(self))]
     pub fn first(&self) -> Option<DefaultSymbol> {
         self.inner.first().map(std::convert::Into::into)
     }
 
+    /// Byte range of each token within the line the record was built from, in token order, for
+    /// mapping tokens back onto the original text. Lines masked by a drain's domain patterns are
+    /// tokenized after masking, so their ranges refer to the masked line.
+    #[instrument(level = "trace", skip(self))]
+    pub fn token_spans(&self) -> Vec<(usize, usize)> {
+        self.inner
+            .inner
+            .iter()
+            .map(|(offset, _)| (offset.start(), offset.end()))
+            .collect()
+    }
+
     #[instrument(level = "trace", skip(self))]
     pub fn len(&self) -> usize {
         self.inner.len()
@@ -77,10 +235,95 @@ impl Record {
         self.inner.len() == 0
     }
 
+    /// Build a new [Record] directly from a list of tokens with a fresh uid
+    #[instrument(name = "Create record from tokens", level = "trace", skip(tokens))]
+    pub fn from_tokens(tokens: Vec<Token>) -> Self {
+        Self {
+            inner: TokenStream::from_tokens(tokens),
+            uid: Ksuid::new(),
+        }
+    }
+
+    /// Build a new [Record] from the tokens in `[start, end)` with a fresh uid.
+    /// Out of range bounds are clamped to the length of the record, so an empty
+    /// range yields an empty record rather than panicking.
+    #[instrument(level = "trace", skip(self))]
+    pub fn subrange(&self, start: usize, end: usize) -> Record {
+        Self {
+            inner: self.inner.subrange(start, end),
+            uid: Ksuid::new(),
+        }
+    }
+
+    /// The distinct resolved tokens of this record, regardless of position
+    #[instrument(level = "trace", skip(self))]
+    pub fn token_set(&self) -> HashSet<String> {
+        self.into_iter().map(|t| t.to_string()).collect()
+    }
+
+    /// Render the record with every token which parses as one of `redact_types` replaced by
+    /// `<REDACTED>`, keeping the original spacing. Opt-in grokkers such as [Grokker::SSN] are
+    /// recognized when listed.
+    #[instrument(level = "trace", skip(self))]
+    pub fn to_redacted_string(&self, redact_types: &[Grokker]) -> String {
+        self.inner
+            .render_mapped(|word| match Token::from_parse_with(&word, redact_types) {
+                Token::TypedMatch(grokker) if redact_types.contains(&grokker) => {
+                    "<REDACTED>".to_string()
+                },
+                _ => word,
+            })
+    }
+
+    /// A 64-bit hash of the resolved tokens, identical across processes and interner states since
+    /// it never looks at symbol ids. Uses 64-bit FNV-1a so the value is stable across releases.
+    #[instrument(level = "trace", skip(self))]
+    pub fn normalized_hash(&self) -> u64 {
+        self.normalized_hash_extended(&[])
+    }
+
+    /// [Record::normalized_hash] continued over `extra` after the tokens, for hashing state kept
+    /// alongside a record
+    #[instrument(level = "trace", skip(self))]
+    pub(crate) fn normalized_hash_extended(&self, extra: &[u8]) -> u64 {
+        const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0000_0100_0000_01b3;
+        let mut hash = OFFSET_BASIS;
+        let mut feed = |bytes: &[u8]| {
+            for b in bytes {
+                hash ^= u64::from(*b);
+                hash = hash.wrapping_mul(PRIME);
+            }
+        };
+        for token in self {
+            // Tag each token with its kind so a wildcard or grokker never collides with a literal
+            // word that renders the same
+            let tag: &[u8] = match token {
+                Token::Wildcard => &[0],
+                Token::TypedMatch(_) => &[1],
+                Token::Value(_) => &[2],
+            };
+            feed(tag);
+            feed(token.to_string().as_bytes());
+            // 0xff never appears in UTF-8 so it can't be confused with token content
+            feed(&[0xff]);
+        }
+        feed(extra);
+        hash
+    }
+
     #[instrument(level = "trace")]
     pub fn resolve(sym: DefaultSymbol) -> Option<String> {
         INTERNER.read().resolve(sym).map(std::borrow::ToOwned::to_owned)
     }
+
+    /// Like [Record::resolve] but borrows the string from the interner instead of copying it. The
+    /// guard holds the interner's read lock, so drop it before creating more records or they'll
+    /// wait on it.
+    #[instrument(level = "trace")]
+    pub fn resolve_ref(sym: DefaultSymbol) -> Option<MappedRwLockReadGuard<'static, str>> {
+        RwLockReadGuard::try_map(INTERNER.read(), |strings| strings.resolve(sym)).ok()
+    }
 }
 
 pub struct IntoIter {
@@ -173,7 +416,13 @@ mod should {
     use proptest::{prelude::*, string::string_regex};
     use spectral::prelude::*;
 
-    use crate::{drains::simple::INTERNER, record::Record};
+    use crate::{
+        drains::simple::INTERNER,
+        record::{
+            tokens::{Grokker, Token, TypedToken},
+            PositionWeighting, Record,
+        },
+    };
 
     prop_compose! {
         fn gen_word()(s in "[[:alpha:]]+") -> String {
@@ -259,6 +508,33 @@ mod should {
         assert_eq!(INTERNER.read().resolve(val).unwrap(), "Message");
     }
 
+    #[test]
+    fn test_token_spans() {
+        let line = "  Connection\tfrom 10.0.0.1  closed: ok\r\n";
+        let rec = Record::new(line.to_string());
+        let words = rec
+            .token_spans()
+            .into_iter()
+            .map(|(start, end)| &line[start..end])
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            words,
+            vec!["Connection", "from", "10.0.0.1", "closed:", "ok"]
+        );
+        assert_eq!(rec.token_spans().len(), rec.len());
+    }
+
+    #[test]
+    fn test_resolve_ref() {
+        let rec = Record::new("Borrowed wombat symbol".to_string());
+        let sym = rec.first().unwrap();
+        assert_eq!(&*Record::resolve_ref(sym).unwrap(), "Borrowed");
+        assert_eq!(
+            Record::resolve_ref(sym).as_deref(),
+            Record::resolve(sym).as_deref()
+        );
+    }
+
     #[test]
     fn test_record_len() {
         let input = "Message send failed to remote host: foo.bar.com".to_string();
@@ -278,6 +554,49 @@ mod should {
         assert_that(&tokens.iter()).contains_all_of(&words.iter());
     }
 
+    #[test]
+    fn test_from_tokens() {
+        let tokens = vec![
+            Token::Value(TypedToken::from_parse("Connection")),
+            Token::Value(TypedToken::from_parse("from")),
+            Token::TypedMatch(Grokker::IPv4),
+            Token::Wildcard,
+        ];
+        let rec = Record::from_tokens(tokens.clone());
+        assert_that(&rec.len()).is_equal_to(4);
+        assert_that(&rec.to_string()).is_equal_to("Connection from IPv4 *".to_string());
+        assert_that(&(&rec).into_iter().collect::<Vec<Token>>()).is_equal_to(tokens);
+        let reparsed = Record::new(rec.to_string());
+        assert_that(&reparsed.to_string()).is_equal_to(rec.to_string());
+    }
+
+    #[test]
+    fn test_subrange() {
+        let input = "Message send failed to remote host: foo.bar.com".to_string();
+        let rec = Record::new(input);
+        let sub = rec.subrange(0, 3);
+        assert_that(&sub.len()).is_equal_to(3);
+        assert_that(&sub.to_string()).is_equal_to("Message send failed".to_string());
+        assert_that(&sub.uid).is_not_equal_to(rec.uid);
+    }
+
+    #[test]
+    fn test_subrange_clamps() {
+        let input = "Message send failed to remote host: foo.bar.com".to_string();
+        let rec = Record::new(input);
+        let tail = rec.subrange(5, 100);
+        assert_that(&tail.to_string()).is_equal_to("host: foo.bar.com".to_string());
+        assert_that(&rec.subrange(9, 12).is_empty()).is_true();
+    }
+
+    #[test]
+    fn test_token_set() {
+        let rec = Record::new("retry the request then retry again".to_string());
+        let set = rec.token_set();
+        assert_that(&set.len()).is_equal_to(5);
+        assert_that(&set.contains("retry")).is_true();
+    }
+
     #[test]
     fn test_non_consuming_iter() {
         let input = "Message send failed to remote host: foo.bar.com".to_string();
@@ -285,4 +604,65 @@ mod should {
         let tokens = (&rec).into_iter().collect::<Vec<_>>();
         assert_that(&tokens).has_length(7);
     }
+
+    #[test]
+    fn test_normalized_hash_is_stable() {
+        let line = "Disk sda1 is 91% full".to_string();
+        let first = Record::new(line.clone());
+        // intern unrelated words so the symbols differ from a fresh interner
+        Record::new("unrelated words interned between records".to_string());
+        let second = Record::new(line);
+        assert_that(&first.normalized_hash()).is_equal_to(second.normalized_hash());
+        // pinned so a change to the hash, which would break cross-process identity, is noticed
+        assert_that(&first.normalized_hash()).is_equal_to(0x0dbf_fad4_233c_ec46);
+        let wildcard = Record::from_tokens(vec![Token::Wildcard]);
+        let literal = Record::new("*".to_string());
+        assert_that(&wildcard.normalized_hash()).is_not_equal_to(literal.normalized_hash());
+    }
+
+    #[test]
+    fn test_tail_weighting_favours_shared_endings() {
+        let a = Record::new("alpha beta gamma delta disk full".to_string());
+        let b = Record::new("one two three four disk full".to_string());
+        let uniform = a.calc_position_weighted_score(&b, PositionWeighting::Uniform);
+        let linear = a.calc_position_weighted_score(&b, PositionWeighting::Linear);
+        let exponential = a.calc_position_weighted_score(&b, PositionWeighting::Exponential);
+        assert!((uniform - 2.0 / 6.0).abs() < f64::EPSILON);
+        assert_that(&linear).is_greater_than(uniform);
+        assert_that(&exponential).is_greater_than(linear);
+    }
+
+    #[test]
+    fn test_to_redacted_string() {
+        let rec = Record::new("login from 10.1.2.3  for  user 078-05-1120".to_string());
+        assert_that(&rec.to_redacted_string(&[Grokker::IPv4, Grokker::SSN]))
+            .is_equal_to("login from <REDACTED>  for  user <REDACTED>".to_string());
+        assert_that(&rec.to_redacted_string(&[])).is_equal_to(rec.to_string());
+    }
+
+    #[test]
+    fn test_calc_sim_score_above() {
+        let rec = Record::new("Backup of volume data completed".to_string());
+        for other in [
+            "Backup of volume data completed",
+            "Backup of volume logs completed",
+            "Restore of volume logs failed",
+            "Nothing in common with this",
+        ] {
+            let other = Record::new(other.to_string());
+            let score = rec.calc_sim_score(&other);
+            for floor in 0..=5 {
+                let expected = (score > floor).then_some(score);
+                assert_that!(rec.calc_sim_score_above(&other, floor)).is_equal_to(expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_lcs_alignment_skips_inserted_words() {
+        let a = Record::new("user alice logged in".to_string());
+        let b = Record::new("user alice has logged in".to_string());
+        assert_that(&a.calc_sim_score(&b)).is_equal_to(2);
+        assert_that(&a.lcs_alignment(&b)).is_equal_to(vec![(0, 0), (1, 1), (2, 3), (3, 4)]);
+    }
 }
@@ -0,0 +1,91 @@
+// Copyright Nicholas Harring. All rights reserved.
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the Server Side Public License, version 1, as published by MongoDB, Inc.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the Server Side Public License for more details. You should have received a copy of the
+// Server Side Public License along with this program.
+// If not, see <http://www.mongodb.com/licensing/server-side-public-license>.
+
+//! Helpers for `#[serde(with = "...")]` on types which don't carry their own serde impls
+
+/// Serialize a [rksuid::Ksuid] using its string form
+pub(crate) mod ksuid {
+    use rksuid::Ksuid;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(uid: &Ksuid, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&uid.serialize())
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Ksuid, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        Ok(Ksuid::deserialize(&text))
+    }
+}
+
+/// Serialize a [string_interner::DefaultSymbol] as the string it resolves to, interning on the way back in
+pub(crate) mod symbol {
+    use serde::{de::Error, ser, Deserialize, Deserializer, Serializer};
+    use string_interner::DefaultSymbol;
+
+    use crate::drains::simple::INTERNER;
+
+    pub(crate) fn serialize<S: Serializer>(
+        sym: &DefaultSymbol,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let interner = INTERNER.read();
+        let text = interner
+            .resolve(*sym)
+            .ok_or_else(|| ser::Error::custom("symbol failed to resolve"))?;
+        serializer.serialize_str(text)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DefaultSymbol, D::Error> {
+        let text = String::deserialize(deserializer).map_err(D::Error::custom)?;
+        Ok(INTERNER.write().get_or_intern(text))
+    }
+}
+
+/// Serialize a map keyed by [string_interner::DefaultSymbol] using the strings the keys resolve to,
+/// interning them on the way back in
+pub(crate) mod symbol_map {
+    use std::collections::HashMap;
+
+    use serde::{ser, ser::SerializeMap, Deserialize, Deserializer, Serialize, Serializer};
+    use string_interner::DefaultSymbol;
+
+    use crate::drains::simple::INTERNER;
+
+    pub(crate) fn serialize<S: Serializer, V: Serialize>(
+        map: &HashMap<DefaultSymbol, V>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let interner = INTERNER.read();
+        let mut out = serializer.serialize_map(Some(map.len()))?;
+        for (sym, value) in map {
+            let text = interner
+                .resolve(*sym)
+                .ok_or_else(|| ser::Error::custom("symbol failed to resolve"))?;
+            out.serialize_entry(text, value)?;
+        }
+        out.end()
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>, V: Deserialize<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<DefaultSymbol, V>, D::Error> {
+        let map = HashMap::<String, V>::deserialize(deserializer)?;
+        let mut interner = INTERNER.write();
+        Ok(map
+            .into_iter()
+            .map(|(text, value)| (interner.get_or_intern(text), value))
+            .collect())
+    }
+}